@@ -0,0 +1,62 @@
+use bytes::Bytes;
+use log::warn;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Downloaded archives saved in a directory with --cache-dir, one file per URL.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: &Path) -> std::io::Result<Cache> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Cache {
+            dir: dir.to_owned(),
+        })
+    }
+
+    /// File of `key`, e.g. `api.chess.com_pub_player_hikaru_games_2023_05_pgn` for an archive URL.
+    fn path(&self, key: &str) -> PathBuf {
+        let name = key
+            .split_once("://")
+            .map_or(key, |(_, rest)| rest)
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        self.dir.join(name)
+    }
+
+    /// The cached body of `key`. Unreadable entries are treated as missing.
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        let path = self.path(key);
+        match std::fs::read(&path) {
+            Ok(body) => Some(Bytes::from(body)),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => {
+                warn!("Failed to read {} from the cache: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Saves `body` for `key`. The file is written under another name first, so that an
+    /// interrupted run doesn't leave a truncated entry behind.
+    pub fn put(&self, key: &str, body: &[u8]) {
+        let path = self.path(key);
+        let saved = tempfile::NamedTempFile::new_in(&self.dir).and_then(|mut file| {
+            file.write_all(body)?;
+            file.persist(&path)?;
+            Ok(())
+        });
+        if let Err(e) = saved {
+            warn!("Failed to save {} to the cache: {}", path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,772 @@
+use clap::builder::RangedU64ValueParser;
+use clap::{value_parser, Parser, ValueEnum};
+use std::path::{Path, PathBuf};
+
+use crate::format::{Encoding, Format};
+use crate::types::{Date, Time};
+
+#[derive(Parser, Clone)]
+#[command(version = "0.3.9", name = "chess_dl", author = "Nimrod Hajaj")]
+/// Chess.com bulk game downloader. By default downloads all time controls and does not sort the games into different files based on time control.
+pub struct Options {
+    #[arg(required_unless_present = "retry_from_report")]
+    pub usernames: Vec<String>,
+    /// Skip the users with fewer rated games than this according to their chess.com stats.
+    #[arg(long, value_name = "N", conflicts_with = "retry_from_report")]
+    pub min_games: Option<usize>,
+
+    /// Don't download the games of this user. Can be repeated.
+    #[arg(long)]
+    pub exclude_user: Vec<String>,
+
+    /// Output directory.
+    #[arg(short, default_value("."), value_parser(value_parser!(PathBuf)))]
+    pub output_dir: PathBuf,
+
+    /// Fail if the output directory doesn't exist instead of creating it.
+    #[arg(long)]
+    pub no_create_dir: bool,
+
+    #[arg(long, display_order = 3)]
+    pub blitz: bool,
+
+    #[arg(long, display_order = 2)]
+    pub bullet: bool,
+
+    #[arg(long, display_order = 4)]
+    pub rapid: bool,
+    /// Only detected with --time-class-from-header.
+    #[arg(long, display_order = 5)]
+    pub daily: bool,
+
+    /// Only keep games that start from a custom position (odds games, variants, ...).
+    #[arg(long, conflicts_with = "no_custom_position")]
+    pub from_position_only: bool,
+
+    /// Skip games that start from a custom position.
+    #[arg(long)]
+    pub no_custom_position: bool,
+
+    /// Skip games of chess variants such as Chess960.
+    #[arg(long)]
+    pub standard_only: bool,
+
+    /// Only keep rated games. Only the JSON archives say whether a game is rated, so this needs
+    /// --json-games.
+    #[arg(long, requires = "json_games")]
+    pub rated_only: bool,
+
+    /// Preset for building a study database: --rated-only, --standard-only, --min-moves 10,
+    /// --skip-aborted and --no-custom-position. An explicit --min-moves takes precedence. Needs
+    /// --json-games for --rated-only.
+    #[arg(long, conflicts_with = "from_position_only", requires = "json_games")]
+    pub study_quality: bool,
+
+    /// Also download the daily games that are still in progress.
+    #[arg(long)]
+    pub include_ongoing: bool,
+
+    /// Skip games the user didn't play in, which occasionally show up in their archives.
+    #[arg(long)]
+    pub played_only: bool,
+
+    /// Only keep games that start with these moves, e.g. "1.e4 e5".
+    #[arg(long, value_name = "MOVES")]
+    pub opening_moves: Option<String>,
+
+    /// Only keep games whose opening name contains this text, ignoring case, e.g. "Najdorf".
+    #[arg(long, value_name = "TEXT")]
+    pub opening_contains: Option<String>,
+
+    /// Only keep games with at least this many moves.
+    #[arg(long)]
+    pub min_moves: Option<u32>,
+
+    /// Skip aborted games.
+    #[arg(long)]
+    pub skip_aborted: bool,
+
+    /// Skip games that are still in progress, i.e. with a "*" result.
+    #[arg(long)]
+    pub finished_only: bool,
+
+    /// Only keep games where the user was checkmated. Combines with --delivered-mate.
+    #[arg(long)]
+    pub checkmated: bool,
+
+    /// Only keep games where the user delivered checkmate. Combines with --checkmated.
+    #[arg(long)]
+    pub delivered_mate: bool,
+
+    /// Only download the archives of this month and later (YYYY-MM-DD, the day is ignored). Use --after to skip the games of earlier days in that month.
+    #[arg(long)]
+    pub from: Option<Date>,
+
+    /// Only download the archives up to this month (YYYY-MM-DD, the day is ignored). Use --before to skip the games of later days in that month.
+    #[arg(long)]
+    pub to: Option<Date>,
+
+    /// Only download these months instead of listing the archives of each user, e.g. 2023/*,
+    /// 2022/01,2022/02 or 2021/11-2022/03.
+    #[arg(long, value_delimiter = ',', value_name = "MONTHS")]
+    pub months: Vec<String>,
+
+    /// Only keep games played on or after this day (YYYY-MM-DD).
+    #[arg(long)]
+    pub after: Option<Date>,
+
+    /// Only keep games played on or before this day (YYYY-MM-DD).
+    #[arg(long)]
+    pub before: Option<Date>,
+
+    /// UTC offset used to determine the day a game was played on with --after and --before, e.g. +02:00 or -5.
+    #[arg(
+        long,
+        default_value("+00:00"),
+        allow_hyphen_values = true,
+        value_parser(parse_utc_offset)
+    )]
+    pub tz: i64,
+
+    /// Use chess.com's TimeClass header to classify games when it is present instead of deriving it from TimeControl.
+    #[arg(long)]
+    pub time_class_from_header: bool,
+
+    /// Sort files by time control.
+    #[arg(short, long, group = "time")]
+    pub timesort: bool,
+
+    /// Write several time classes to the same files, e.g. blitz,rapid=Fast. Can be repeated. Filters still use the original time classes.
+    #[arg(
+        long,
+        requires = "timesort",
+        value_name = "TIMES=NAME",
+        value_parser(parse_time_group)
+    )]
+    pub merge_time: Vec<(Vec<Time>, String)>,
+
+    /// Treat the games of some time classes as another time class, e.g. daily=rapid. Can be
+    /// repeated. Unlike --merge-time, the filters and file names both use the new time class.
+    #[arg(long, value_name = "TIMES=TIME", value_parser(parse_time_remap))]
+    pub remap_time: Vec<(Vec<Time>, Time)>,
+
+    /// Sort files by chess variant, e.g. username_White_Chess960_Blitz.pgn. Standard games are not affected.
+    #[arg(long)]
+    pub variantsort: bool,
+
+    /// Write the games of each monthly archive to their own file, e.g. username_2023-05.pgn, instead of grouping them by color, time control or variant.
+    #[arg(long, conflicts_with_all(&["timesort", "variantsort"]))]
+    pub by_archive: bool,
+
+    /// Write the games of each year to their own files, e.g. username_White_2023.pgn. Games
+    /// without a date go to the NoYear files.
+    #[arg(long, conflicts_with = "by_archive")]
+    pub by_year: bool,
+
+    /// Leave the username out of file names when downloading a single user, e.g. White_Blitz.pgn.
+    /// Has no effect with several users, so that their files don't collide.
+    #[arg(long)]
+    pub flatten_single_user: bool,
+
+    /// File of `handle=canonical` lines. The games of each handle are written to the files of its
+    /// canonical name, e.g. to merge several accounts of the same person.
+    #[arg(long, value_parser(value_parser!(PathBuf)), conflicts_with = "group_users")]
+    pub aliases: Option<PathBuf>,
+
+    /// Sort the games into files by the name of their opening. Games without one go to the
+    /// UnknownOpening files.
+    #[arg(long, conflicts_with = "by_archive")]
+    pub group_by_opening_name: bool,
+
+    /// Name the files of each user with the casing of their name in the games, e.g. Hikaru instead
+    /// of hikaru.
+    #[arg(long)]
+    pub normalize_usernames_from_pgn: bool,
+
+    /// Write the games of all users into the same files.
+    #[arg(long)]
+    pub group_users: bool,
+
+    /// Put the games of each user together, after a `{ === user: name === }` comment, when grouping
+    /// users.
+    #[arg(
+        long,
+        requires = "group_users",
+        conflicts_with_all(&["format", "merge_into", "newest_first_output", "split_games", "raw"])
+    )]
+    pub user_sections: bool,
+
+    /// Add DownloadedAt and SourceArchive tags with the time the games were written and the URL of
+    /// their archive to each game.
+    #[arg(long)]
+    pub stamp_provenance: bool,
+
+    /// Add a DownloadedFor tag with the username to each game when grouping users.
+    #[arg(long, requires = "group_users")]
+    pub tag_player: bool,
+
+    /// Downloads raw files and does no parsing. This conflicts with any flag that depends on parsing.
+    #[arg(long, conflicts_with_all(&[
+        "blitz", "bullet", "rapid", "daily", "timesort", "variantsort",
+        "time_class_from_header", "from_position_only", "no_custom_position", "merge_into",
+        "pretty", "filter_header", "headers_only", "tag_player", "checkmated", "delivered_mate",
+        "study_quality", "min_moves", "skip_aborted", "after", "before", "tz", "exclude_ids",
+        "include_ids", "played_only", "max_new_games", "parse_only", "keep_tags", "drop_tags",
+        "format", "stdout", "newest_first_output", "parallel_parse", "max_parse_error_rate",
+        "finished_only", "color_balance_report", "json_games", "opening_moves", "split_games",
+        "dedupe_global", "opening_contains", "group_by_opening_name", "sample", "dedupe_by_moves",
+        "strip_comments", "verify", "by_year", "stats_per_opponent", "toc", "on_parse_error",
+        "remap_time", "stamp_provenance", "dump_unparsed", "standard_only", "rated_only",
+    ]))]
+    pub raw: bool,
+
+    /// Download the archives as JSON instead of PGN and take the players, result, time class and variant from its fields.
+    #[arg(long, conflicts_with_all(&["include_ongoing", "parallel_parse"]))]
+    pub json_games: bool,
+
+    /// Only keep analyzed games where the user's accuracy was at least this high.
+    #[arg(long, requires = "json_games")]
+    pub min_accuracy: Option<f32>,
+
+    /// Skip the games listed in this file, one game ID or link per line.
+    #[arg(long, value_parser(value_parser!(PathBuf)))]
+    pub exclude_ids: Option<PathBuf>,
+
+    /// Only keep the first copy of games that are in the archives of several users, e.g. games
+    /// between two of the downloaded users.
+    #[arg(long)]
+    pub dedupe_global: bool,
+
+    /// Only keep the first of the games with the same moves, ignoring their header tags, comments
+    /// and formatting, e.g. games imported several times.
+    #[arg(long)]
+    pub dedupe_by_moves: bool,
+
+    /// Remove the comments from the moves, e.g. the clock annotations.
+    #[arg(long)]
+    pub strip_comments: bool,
+
+    /// Only keep the games listed in this file, one game ID or link per line.
+    #[arg(long, value_parser(value_parser!(PathBuf)))]
+    pub include_ids: Option<PathBuf>,
+
+    /// Only keep games whose header tag KEY contains VALUE. Can be repeated, in which case all of them must match.
+    #[arg(long, value_name = "KEY=VALUE", value_parser(parse_key_val))]
+    pub filter_header: Vec<(String, String)>,
+
+    /// Require --filter-header values to match exactly instead of as substrings.
+    #[arg(long, requires = "filter_header")]
+    pub filter_exact: bool,
+
+    /// Only check that the downloaded games can be parsed, without writing any files. Fails if any
+    /// of them can't.
+    #[arg(long, conflicts_with_all(&["merge_into", "manifest"]))]
+    pub parse_only: bool,
+
+    /// Only write these header tags, e.g. White,Black,Result. Tags added by other options are kept.
+    #[arg(long, value_delimiter = ',', conflicts_with = "drop_tags")]
+    pub keep_tags: Vec<String>,
+
+    /// Leave these header tags out, e.g. Link,CurrentPosition.
+    #[arg(long, value_delimiter = ',')]
+    pub drop_tags: Vec<String>,
+
+    /// Format of the output files.
+    #[arg(long, value_enum, default_value_t, conflicts_with = "merge_into")]
+    pub format: Format,
+
+    /// Character encoding of the output files.
+    #[arg(long, value_enum, default_value_t, conflicts_with_all(&["merge_into", "stdout"]))]
+    pub encoding: Encoding,
+
+    /// Only write a random sample of this many of the games that pass the filters. The sampled
+    /// games are kept in memory until all archives are parsed.
+    #[arg(long, value_name = "N", conflicts_with = "stdout")]
+    pub sample: Option<usize>,
+
+    /// Sample --sample games of each user instead of across all users.
+    #[arg(long, requires = "sample")]
+    pub sample_per_user: bool,
+
+    /// Write each game to its own file named by its date and players, e.g.
+    /// `2023-05-01_white_vs_black.pgn`, instead of grouping the games.
+    #[arg(long, conflicts_with_all(&["merge_into", "stdout", "newest_first_output"]))]
+    pub split_games: bool,
+
+    /// Print each game as a JSON line to stdout as soon as it is downloaded instead of writing files.
+    #[arg(long, conflicts_with_all(&["format", "merge_into", "manifest", "parse_only", "summary_only"]))]
+    pub stdout: bool,
+
+    /// Write the games of each file from newest to oldest. This keeps all games of a file in memory while writing it.
+    #[arg(long, conflicts_with_all(&["format", "merge_into", "stdout", "headers_only"]))]
+    pub newest_first_output: bool,
+
+    /// What to do with games that can't be parsed: skip them, fail the run, or also write the raw
+    /// archive they came from to a `*_unparsed.pgn` file in the output directory.
+    #[arg(long, value_enum, default_value_t)]
+    pub on_parse_error: ParseErrorPolicy,
+
+    /// Write each game or archive that fails to parse to a file in this directory, with the URL it
+    /// came from and the parse error.
+    #[arg(long, value_name = "DIR", value_parser(value_parser!(PathBuf)))]
+    pub dump_unparsed: Option<PathBuf>,
+
+    /// Stop writing to --dump-unparsed after this many files.
+    #[arg(long, default_value_t = 100, requires = "dump_unparsed")]
+    pub max_unparsed_dumps: usize,
+
+    /// Fail if more than this percentage of the games can't be parsed.
+    #[arg(long, value_name = "PERCENT")]
+    pub max_parse_error_rate: Option<f64>,
+
+    /// Add the time left on the clock after each move to the JSON, JSONL and CSV output, from the
+    /// clock annotations of the moves.
+    #[arg(long)]
+    pub export_clocks: bool,
+
+    /// Reformat the games with the standard header order and wrapped moves.
+    #[arg(long)]
+    pub pretty: bool,
+
+    /// Check that each game still parses into the same game after options like --pretty or
+    /// --strip-comments rewrote it, and fail if one doesn't.
+    #[arg(long, conflicts_with = "headers_only")]
+    pub verify: bool,
+
+    /// Only write the header tags of each game, without the moves.
+    #[arg(long, conflicts_with = "merge_into")]
+    pub headers_only: bool,
+
+    /// Merge the downloaded games into the existing files in this directory, skipping games that are already there.
+    #[arg(long, value_parser(value_parser!(PathBuf)))]
+    pub merge_into: Option<PathBuf>,
+
+    /// Remember when each archive was last modified in this file, and skip the archives that haven't changed since. Requires --merge-into so that their games are already in the output files.
+    #[arg(long, requires = "merge_into", value_parser(value_parser!(PathBuf)))]
+    pub sync_state: Option<PathBuf>,
+
+    /// Also merge the games downloaded so far and save --sync-state after every N archives, so that
+    /// an interrupted run only downloads the archives since the last save again.
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "sync_state",
+        conflicts_with_all(&["sample", "normalize_usernames_from_pgn"]),
+        value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+    )]
+    pub checkpoint_every: Option<usize>,
+
+    /// Keep running and download the new games every --interval minutes until interrupted. Only
+    /// the archives that changed are downloaded again, see --sync-state.
+    #[arg(long, requires = "sync_state")]
+    pub follow: bool,
+
+    /// Minutes to wait between the runs of --follow.
+    #[arg(
+        long,
+        default_value("60"),
+        requires = "follow",
+        value_parser(value_parser!(u64).range(1..))
+    )]
+    pub interval: u64,
+
+    /// Write a tab-separated index of the output files to this file, with a line per game giving
+    /// its byte offset in its output file, players, date and result. This keeps all games of a
+    /// file in memory while writing it.
+    #[arg(
+        long,
+        value_name = "PATH",
+        value_parser(value_parser!(PathBuf)),
+        conflicts_with_all(&[
+            "format",
+            "merge_into",
+            "split_games",
+            "stdout",
+            "user_sections",
+            "headers_only",
+        ])
+    )]
+    pub toc: Option<PathBuf>,
+
+    /// Write a JSON list of the output files with their group, number of games and size.
+    #[arg(long, value_parser(value_parser!(PathBuf)))]
+    pub manifest: Option<PathBuf>,
+
+    /// Only print the archive URLs of each user without downloading any games.
+    #[arg(long)]
+    pub list_archives: bool,
+
+    /// Only print how many games of each time class every user has played, without downloading any games.
+    #[arg(long, conflicts_with = "list_archives")]
+    pub probe: bool,
+
+    /// Only print the current and best rating and the wins, losses and draws of every user for
+    /// each time class, without downloading any games.
+    #[arg(long, conflicts_with_all = ["list_archives", "probe"])]
+    pub stats: bool,
+
+    /// Print how many games were played with white and with black for each time class.
+    #[arg(long)]
+    pub color_balance_report: bool,
+
+    /// Print the wins, losses and draws of each user against their N most frequent opponents.
+    /// With --report-json, the records against all opponents are written to the report.
+    #[arg(long, value_name = "N")]
+    pub stats_per_opponent: Option<usize>,
+
+    /// Only download the archives that failed in the run that wrote this --report-json, with the
+    /// same options as that run. The usernames are taken from the archive URLs.
+    #[arg(
+        long,
+        value_name = "PATH",
+        value_parser(value_parser!(PathBuf)),
+        conflicts_with_all(&["usernames", "list_archives", "probe", "stats", "follow", "include_ongoing"])
+    )]
+    pub retry_from_report: Option<PathBuf>,
+
+    /// Write a JSON report to this file, with the number of archives by outcome and the archives
+    /// that failed.
+    #[arg(long, value_parser(value_parser!(PathBuf)))]
+    pub report_json: Option<PathBuf>,
+
+    /// Don't color the log levels. Colors are also disabled by the NO_COLOR environment variable
+    /// and when the log isn't written to a terminal.
+    #[arg(long)]
+    pub no_color: bool,
+
+    /// Only log the download summary and problems, not the progress of each archive. The summary
+    /// is printed to stdout so that it is shown regardless of the log level.
+    #[arg(long)]
+    pub summary_only: bool,
+
+    /// Download the most recent archives first.
+    #[arg(long)]
+    pub newest_first: bool,
+
+    /// Download the archives of all users in turns instead of one user after the other, so that a
+    /// run that is stopped early covers every user.
+    #[arg(long)]
+    pub fair_ordering: bool,
+
+    /// Stop downloading once this many games passed the filters. Combine with --newest-first to only fetch the latest games.
+    #[arg(long)]
+    pub max_new_games: Option<usize>,
+
+    /// Save the archives and archive lists in this directory, and read them from it on later runs
+    /// instead of downloading them again.
+    #[arg(long, value_parser(value_parser!(PathBuf)))]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Only read the archives from --cache-dir, without connecting to chess.com.
+    #[arg(long, requires = "cache_dir", conflicts_with = "include_ongoing")]
+    pub offline: bool,
+
+    /// Download the archive of the current month again even if it is cached, since it still gets
+    /// new games.
+    #[arg(long, requires = "cache_dir", conflicts_with = "offline")]
+    pub refresh: bool,
+
+    /// Base URL of the chess.com API, e.g. to go through a caching proxy.
+    #[arg(long, default_value("https://api.chess.com/pub"))]
+    pub api_base: String,
+
+    /// Number of download attempts for each archive.
+    #[arg(short, long, default_value("8"))]
+    pub attempts: u32,
+
+    /// How the delay between retries grows: 1, 2, 4, ... seconds (capped at 64), 1, 2, 3, ... seconds, or always 1 second.
+    #[arg(long, value_enum, default_value_t)]
+    pub backoff: Backoff,
+
+    /// Wait exactly as long as --backoff says between retries instead of randomizing the delays.
+    #[arg(long)]
+    pub no_jitter: bool,
+
+    /// Seconds an idle connection is kept open for reuse.
+    #[arg(long, default_value("90"))]
+    pub pool_idle_timeout: u64,
+
+    /// Redirects followed for a request before it fails.
+    #[arg(long, default_value("10"))]
+    pub max_redirects: usize,
+
+    /// Use HTTP/2 without negotiating it first, so that requests are multiplexed over fewer connections.
+    #[arg(long)]
+    pub http2: bool,
+
+    /// Only connect over IPv4 or IPv6, e.g. when the other one is unreliable on this network.
+    #[arg(long, value_enum, default_value_t)]
+    pub ip_family: IpFamily,
+
+    /// Read at most this many bytes per second over all downloads.
+    #[arg(long, value_name = "BYTES", value_parser(value_parser!(u64).range(1..)))]
+    pub max_bps: Option<u64>,
+
+    /// Wait at least this many milliseconds between starting the download of each archive. With
+    /// more than one concurrent download, this staggers their start instead of pausing between them.
+    #[arg(long)]
+    pub archive_delay_ms: Option<u64>,
+
+    /// Skip archives larger than this many bytes.
+    #[arg(long)]
+    pub max_archive_bytes: Option<u64>,
+
+    /// Give up on an archive if downloading it, including all retries, takes longer than this many seconds.
+    #[arg(long)]
+    pub archive_deadline_secs: Option<u64>,
+
+    /// Don't start if the file system of the output directory has less than this many bytes free.
+    #[arg(long, value_name = "BYTES")]
+    pub min_free_space: Option<u64>,
+
+    /// Stop after this many seconds like an interruption, saving the games downloaded so far. If
+    /// saving them takes more than another 30 seconds, exit anyway, which can leave output files
+    /// partially written.
+    #[arg(long, value_name = "SECS")]
+    pub hard_timeout_secs: Option<u64>,
+
+    /// Skip the remaining archives of a user after this many of their archives in a row were
+    /// empty, which can mean that the API has problems.
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+    )]
+    pub max_empty_archives_before_stop: Option<usize>,
+
+    /// Stop downloading once the archives add up to this many bytes, keeping the games downloaded so far.
+    #[arg(long)]
+    pub max_total_bytes: Option<u64>,
+
+    /// Number of threads parsing and writing the downloaded games.
+    #[arg(
+        long,
+        default_value("1"),
+        value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+    )]
+    pub writers: usize,
+
+    /// Number of users whose archives are listed concurrently.
+    #[arg(
+        long,
+        default_value("4"),
+        value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+    )]
+    pub list_concurrency: usize,
+
+    /// Parse the games of each archive on all cores. Mostly useful with a single writer thread.
+    #[arg(long)]
+    pub parallel_parse: bool,
+
+    /// Number of concurrent downloads, or "auto" to choose one from the number of CPUs. Too many would cause downloads to fail, but higher is usually faster.
+    #[arg(short, long, default_value("10"), value_parser(parse_concurrency))]
+    pub concurrent: Concurrency,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Concurrency {
+    Auto,
+    Fixed(usize),
+}
+
+impl Concurrency {
+    /// Number of concurrent downloads. `Auto` is twice the available parallelism, between 4 and 16.
+    pub fn resolve(self) -> usize {
+        match self {
+            Concurrency::Fixed(concurrent) => concurrent,
+            Concurrency::Auto => std::thread::available_parallelism()
+                .map_or(4, |cpus| cpus.get() * 2)
+                .clamp(4, 16),
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum ParseErrorPolicy {
+    #[default]
+    Skip,
+    Abort,
+    RawFallback,
+}
+
+#[derive(ValueEnum, Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum IpFamily {
+    #[default]
+    Auto,
+    V4,
+    V6,
+}
+
+#[derive(ValueEnum, Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum Backoff {
+    #[default]
+    Exponential,
+    Linear,
+    Constant,
+}
+
+impl Options {
+    /// Whether any output files are written.
+    pub fn writes_files(&self) -> bool {
+        !(self.parse_only || self.stdout)
+    }
+
+    /// Directory the output files are written to.
+    pub fn destination_dir(&self) -> &Path {
+        self.merge_into.as_deref().unwrap_or(&self.output_dir)
+    }
+
+    /// Level of the logs about the progress of each archive and file.
+    pub fn progress_level(&self) -> log::Level {
+        if self.summary_only {
+            log::Level::Debug
+        } else {
+            log::Level::Info
+        }
+    }
+
+    /// Turns presets into the options they stand for, keeping explicitly set values.
+    pub fn apply_presets(&mut self) {
+        if self.study_quality {
+            self.rated_only = true;
+            self.standard_only = true;
+            self.min_moves.get_or_insert(10);
+            self.skip_aborted = true;
+            self.no_custom_position = true;
+        }
+    }
+}
+
+fn parse_key_val(s: &str) -> Result<(String, String), String> {
+    let (key, val) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, found `{}`", s))?;
+    Ok((key.to_owned(), val.to_owned()))
+}
+
+/// Parses a comma separated list of time classes and the name of their group.
+fn parse_time_group(s: &str) -> Result<(Vec<Time>, String), String> {
+    let (times, name) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected TIMES=NAME, found `{}`", s))?;
+    if name.is_empty() {
+        return Err(format!("missing the name of the group in `{}`", s));
+    }
+    let times = times
+        .split(',')
+        .map(|time| {
+            Time::from_time_class(&time.to_lowercase())
+                .ok_or_else(|| format!("unknown time class `{}`", time))
+        })
+        .collect::<Result<Vec<Time>, String>>()?;
+    Ok((times, name.to_owned()))
+}
+
+/// Parses `TIMES=TIME` like --merge-time, with a time class as the name.
+fn parse_time_remap(s: &str) -> Result<(Vec<Time>, Time), String> {
+    let (times, name) = parse_time_group(s)?;
+    let time = Time::from_time_class(&name.to_lowercase())
+        .ok_or_else(|| format!("unknown time class `{}`", name))?;
+    Ok((times, time))
+}
+
+/// Parses a positive number or `auto`.
+fn parse_concurrency(s: &str) -> Result<Concurrency, String> {
+    if s.eq_ignore_ascii_case("auto") {
+        return Ok(Concurrency::Auto);
+    }
+    match s.parse() {
+        Ok(concurrent) if concurrent > 0 => Ok(Concurrency::Fixed(concurrent)),
+        _ => Err(format!(
+            "expected a positive number or `auto`, found `{}`",
+            s
+        )),
+    }
+}
+
+/// Parses `[+-]HH[:MM]` into seconds.
+fn parse_utc_offset(s: &str) -> Result<i64, String> {
+    let err = || format!("expected an offset like +02:00 or -5, found `{}`", s);
+    let (sign, rest) = match s.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+    let hours: i64 = hours.parse().map_err(|_| err())?;
+    let minutes: i64 = minutes.parse().map_err(|_| err())?;
+    if hours > 14 || minutes >= 60 {
+        return Err(err());
+    }
+    Ok(sign * (hours * 3600 + minutes * 60))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_val() {
+        assert_eq!(
+            parse_key_val("Event=Club = A"),
+            Ok(("Event".to_owned(), "Club = A".to_owned()))
+        );
+        assert_eq!(
+            parse_key_val("Annotator="),
+            Ok(("Annotator".to_owned(), String::new()))
+        );
+        assert!(parse_key_val("Event").is_err());
+    }
+
+    #[test]
+    fn study_quality_preset() {
+        let mut opt = Options::parse_from(["chess_dl", "u", "--study-quality", "--json-games"]);
+        opt.apply_presets();
+        assert!(opt.rated_only && opt.standard_only && opt.skip_aborted && opt.no_custom_position);
+        assert_eq!(opt.min_moves, Some(10));
+
+        let mut opt = Options::parse_from([
+            "chess_dl",
+            "u",
+            "--study-quality",
+            "--json-games",
+            "--min-moves",
+            "20",
+        ]);
+        opt.apply_presets();
+        assert_eq!(opt.min_moves, Some(20));
+
+        // The PGN archives don't say which games are rated.
+        assert!(Options::try_parse_from(["chess_dl", "u", "--study-quality"]).is_err());
+    }
+
+    #[test]
+    fn follow_interval() {
+        let args = [
+            "chess_dl",
+            "u",
+            "--sync-state",
+            "s.json",
+            "--merge-into",
+            "u.pgn",
+            "--follow",
+            "--interval",
+        ];
+        let opt = Options::try_parse_from(args.iter().chain(&["5"])).unwrap();
+        assert_eq!(opt.interval, 5);
+        assert!(Options::try_parse_from(args.iter().chain(&["0"])).is_err());
+    }
+
+    #[test]
+    fn toc_needs_moves() {
+        let args = ["chess_dl", "u", "--toc", "toc.tsv"];
+        assert!(Options::try_parse_from(args).is_ok());
+        // The games are parsed again for the index, which needs their moves.
+        assert!(Options::try_parse_from(args.iter().chain(&["--headers-only"])).is_err());
+    }
+
+    #[test]
+    fn verify_cli() {
+        use clap::CommandFactory;
+        Options::command().debug_assert();
+    }
+}
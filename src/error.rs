@@ -0,0 +1,47 @@
+use std::path::PathBuf;
+
+use crate::parse::Rule;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DownloadError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("failed to list the archives of {username}: {source}")]
+    ArchiveListFailed {
+        username: String,
+        source: reqwest::Error,
+    },
+    #[error("failed to parse PGN: {source}")]
+    Parse {
+        source: Box<pest::error::Error<Rule>>,
+    },
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("invalid game ID or link in {}: `{line}`", path.display())]
+    InvalidGameId { path: PathBuf, line: String },
+    #[error("invalid alias in {}: `{line}`, expected `handle=canonical`", path.display())]
+    InvalidAlias { path: PathBuf, line: String },
+    #[error("can't tell whose games are at {0}")]
+    UnknownArchiveUser(String),
+    #[error("{0} is not in the cache")]
+    NotCached(String),
+    #[error("output directory {} does not exist", .0.display())]
+    MissingOutputDir(PathBuf),
+    #[error("only {free} bytes are free in {}, less than the required {min}", dir.display())]
+    NotEnoughSpace { dir: PathBuf, free: u64, min: u64 },
+    #[error("{0} games failed to parse")]
+    InvalidGames(usize),
+    #[error("{0} games were not valid PGN after rewriting them")]
+    UnverifiedGames(usize),
+    #[error("{rate:.2}% of the games failed to parse, more than the allowed {max}%")]
+    TooManyParseErrors { rate: f64, max: f64 },
+    #[error("the download was cancelled")]
+    Cancelled,
+    #[error("writer thread panicked")]
+    WriterPanicked,
+}
@@ -0,0 +1,425 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::error::DownloadError;
+use crate::types::Game;
+#[cfg(feature = "sqlite")]
+use serde::Deserialize;
+use std::path::Path;
+
+/// How the games are written to the output files.
+#[derive(ValueEnum, Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum Format {
+    #[default]
+    Pgn,
+    /// A JSON array of games per file.
+    Json,
+    /// One JSON object per line.
+    Jsonl,
+    /// One row per game, without the header tags that don't have their own column.
+    Csv,
+    /// A SQLite database per file with a `games` table, always in UTF-8.
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+const CSV_COLUMNS: [&str; 12] = [
+    "id",
+    "date",
+    "white",
+    "black",
+    "result",
+    "time",
+    "variant",
+    "fen",
+    "white_accuracy",
+    "black_accuracy",
+    "clocks",
+    "moves",
+];
+
+#[derive(Serialize)]
+struct GameRecord<'a> {
+    id: Option<u64>,
+    /// UTC day the game started on.
+    date: Option<String>,
+    timestamp: Option<i64>,
+    white: &'a str,
+    black: &'a str,
+    result: &'a str,
+    time: String,
+    variant: Option<&'a str>,
+    opening: Option<&'a str>,
+    fen: Option<&'a str>,
+    white_accuracy: Option<f32>,
+    black_accuracy: Option<f32>,
+    /// Seconds left on the clock after each move.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    clocks: Vec<f64>,
+    headers: BTreeMap<&'a str, &'a str>,
+    moves: &'a str,
+}
+
+impl<'a> GameRecord<'a> {
+    fn new(game: &'a Game) -> GameRecord<'a> {
+        GameRecord {
+            id: game.id,
+            date: game.local_date(0).map(|date| date.to_string()),
+            timestamp: game.timestamp,
+            white: &game.white_display,
+            black: &game.black_display,
+            result: game.header("Result").unwrap_or_default(),
+            time: game.time.to_string(),
+            variant: game.variant.as_deref(),
+            opening: game.opening.as_deref(),
+            fen: game.fen.as_deref(),
+            white_accuracy: game.white_accuracy,
+            black_accuracy: game.black_accuracy,
+            clocks: game
+                .clocks
+                .iter()
+                .map(|clock| clock.as_secs_f64())
+                .collect(),
+            headers: game
+                .headers
+                .iter()
+                .map(|(attr, val)| (attr.as_str(), val.as_str()))
+                .collect(),
+            moves: game.moves.trim(),
+        }
+    }
+
+    fn csv_row(&self) -> String {
+        let id = self.id.map(|id| id.to_string()).unwrap_or_default();
+        let white_accuracy = self
+            .white_accuracy
+            .map(|accuracy| accuracy.to_string())
+            .unwrap_or_default();
+        let black_accuracy = self
+            .black_accuracy
+            .map(|accuracy| accuracy.to_string())
+            .unwrap_or_default();
+        let clocks = self
+            .clocks
+            .iter()
+            .map(|clock| clock.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let fields = [
+            id.as_str(),
+            self.date.as_deref().unwrap_or_default(),
+            self.white,
+            self.black,
+            self.result,
+            &self.time,
+            self.variant.unwrap_or_default(),
+            self.fen.unwrap_or_default(),
+            &white_accuracy,
+            &black_accuracy,
+            &clocks,
+            self.moves,
+        ];
+        let mut row = fields
+            .iter()
+            .map(|field| csv_field(field))
+            .collect::<Vec<_>>()
+            .join(",");
+        row.push('\n');
+        row
+    }
+}
+
+/// Quotes `field` if it contains a separator, quote or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Pgn => "pgn",
+            Format::Json => "json",
+            Format::Jsonl => "jsonl",
+            Format::Csv => "csv",
+            #[cfg(feature = "sqlite")]
+            Format::Sqlite => "sqlite",
+        }
+    }
+
+    /// Writes a game to a temporary file. JSON games are written one per line and only put into
+    /// an array by `write_file`, so that temporary files can simply be concatenated. SQLite games
+    /// are written as JSON lines too and only inserted into the database by `write_sqlite`.
+    pub fn write_game<W: Write>(self, out: &mut W, game: &Game) -> std::io::Result<()> {
+        match self {
+            Format::Pgn => out.write_all(game.pgn.as_bytes()),
+            #[cfg(feature = "sqlite")]
+            Format::Sqlite => {
+                serde_json::to_writer(&mut *out, &GameRecord::new(game))?;
+                out.write_all(b"\n")
+            }
+            Format::Json | Format::Jsonl => {
+                serde_json::to_writer(&mut *out, &GameRecord::new(game))?;
+                out.write_all(b"\n")
+            }
+            Format::Csv => out.write_all(GameRecord::new(game).csv_row().as_bytes()),
+        }
+    }
+
+    /// Copies a temporary file written by `write_game` to the output file. SQLite databases are
+    /// written by `write_sqlite` instead.
+    pub fn write_file<R: Read, W: Write>(
+        self,
+        mut tmp_file: R,
+        out: &mut W,
+    ) -> std::io::Result<()> {
+        match self {
+            Format::Pgn | Format::Jsonl => std::io::copy(&mut tmp_file, out).map(|_| ()),
+            Format::Json => {
+                let mut separator = "[\n";
+                for line in BufReader::new(tmp_file).lines() {
+                    write!(out, "{}{}", separator, line?)?;
+                    separator = ",\n";
+                }
+                let end = if separator == "[\n" { "[]\n" } else { "\n]\n" };
+                out.write_all(end.as_bytes())
+            }
+            Format::Csv => {
+                writeln!(out, "{}", CSV_COLUMNS.join(","))?;
+                std::io::copy(&mut tmp_file, out).map(|_| ())
+            }
+            #[cfg(feature = "sqlite")]
+            Format::Sqlite => unreachable!("SQLite databases are written by write_database"),
+        }
+    }
+
+    /// Writes the games of a temporary file written by `write_game` to a new database at `path`,
+    /// replacing the file there. Returns the size of the database, or `None` if this format is
+    /// written by `write_file` instead.
+    #[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+    pub fn write_database<R: Read>(
+        self,
+        tmp_file: R,
+        path: &Path,
+    ) -> Result<Option<u64>, DownloadError> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            Format::Sqlite => {
+                match std::fs::remove_file(path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => (),
+                }
+                write_sqlite(tmp_file, path).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Inserts the games of a temporary file written by `write_game` into a new `games` table of the
+/// database at `path`. Returns the size of the database.
+#[cfg(feature = "sqlite")]
+fn write_sqlite<R: Read>(tmp_file: R, path: &Path) -> Result<u64, DownloadError> {
+    use rusqlite::params;
+
+    let mut db = rusqlite::Connection::open(path)?;
+    let tx = db.transaction()?;
+    tx.execute_batch(
+        "CREATE TABLE games (
+            id INTEGER,
+            date TEXT,
+            timestamp INTEGER,
+            white TEXT NOT NULL,
+            black TEXT NOT NULL,
+            result TEXT NOT NULL,
+            time TEXT NOT NULL,
+            variant TEXT,
+            opening TEXT,
+            fen TEXT,
+            white_accuracy REAL,
+            black_accuracy REAL,
+            clocks TEXT,
+            headers TEXT NOT NULL,
+            moves TEXT NOT NULL
+        );",
+    )?;
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO games VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+        )?;
+        for line in BufReader::new(tmp_file).lines() {
+            let record: SqliteRecord = serde_json::from_str(&line?)?;
+            let clocks = record
+                .clocks
+                .iter()
+                .map(|clock| clock.to_string())
+                .collect::<Vec<_>>()
+                .join(" ");
+            insert.execute(params![
+                record.id.map(|id| id as i64),
+                record.date,
+                record.timestamp,
+                record.white,
+                record.black,
+                record.result,
+                record.time,
+                record.variant,
+                record.opening,
+                record.fen,
+                record.white_accuracy,
+                record.black_accuracy,
+                (!clocks.is_empty()).then_some(clocks),
+                serde_json::to_string(&record.headers)?,
+                record.moves,
+            ])?;
+        }
+    }
+    tx.commit()?;
+    drop(db);
+    Ok(std::fs::metadata(path)?.len())
+}
+
+/// A `GameRecord` read back from a temporary file.
+#[cfg(feature = "sqlite")]
+#[derive(Deserialize)]
+struct SqliteRecord {
+    id: Option<u64>,
+    date: Option<String>,
+    timestamp: Option<i64>,
+    white: String,
+    black: String,
+    result: String,
+    time: String,
+    variant: Option<String>,
+    opening: Option<String>,
+    fen: Option<String>,
+    white_accuracy: Option<f32>,
+    black_accuracy: Option<f32>,
+    #[serde(default)]
+    clocks: Vec<f64>,
+    headers: BTreeMap<String, String>,
+    moves: String,
+}
+
+/// Character encoding of the output files.
+#[derive(ValueEnum, Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    /// ISO 8859-1, for tools that don't read UTF-8. Other characters are written as `?`.
+    Latin1,
+}
+
+impl Encoding {
+    /// Wraps `out` so that the UTF-8 written to it is converted to this encoding.
+    pub fn writer<W: Write + 'static>(self, out: W) -> Box<dyn Write> {
+        match self {
+            Encoding::Utf8 => Box::new(out),
+            Encoding::Latin1 => Box::new(Latin1Writer {
+                out,
+                pending: Vec::new(),
+            }),
+        }
+    }
+
+    /// Number of bytes `s` takes in this encoding.
+    pub fn encoded_len(self, s: &str) -> u64 {
+        match self {
+            Encoding::Utf8 => s.len() as u64,
+            Encoding::Latin1 => s.chars().count() as u64,
+        }
+    }
+}
+
+/// Converts UTF-8 to Latin-1. A character split across writes is kept until its last byte arrives.
+struct Latin1Writer<W: Write> {
+    out: W,
+    pending: Vec<u8>,
+}
+
+impl<W: Write> Write for Latin1Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let mut latin1 = Vec::with_capacity(self.pending.len());
+        let mut rest = &self.pending[..];
+        loop {
+            let (valid, invalid) = match std::str::from_utf8(rest) {
+                Ok(valid) => (valid, None),
+                Err(e) => (
+                    // Checked by from_utf8.
+                    std::str::from_utf8(&rest[..e.valid_up_to()]).unwrap(),
+                    Some(e),
+                ),
+            };
+            latin1.extend(valid.chars().map(|c| u8::try_from(c).unwrap_or(b'?')));
+            match invalid {
+                Some(e) => match e.error_len() {
+                    Some(len) => {
+                        latin1.push(b'?');
+                        rest = &rest[e.valid_up_to() + len..];
+                    }
+                    None => {
+                        rest = &rest[e.valid_up_to()..];
+                        break;
+                    }
+                },
+                None => {
+                    rest = &[];
+                    break;
+                }
+            }
+        }
+        self.out.write_all(&latin1)?;
+        self.pending = rest.to_vec();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latin1(parts: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut writer = Latin1Writer {
+            out: &mut out,
+            pending: Vec::new(),
+        };
+        for part in parts {
+            assert_eq!(writer.write(part).unwrap(), part.len());
+        }
+        out
+    }
+
+    #[test]
+    fn latin1_non_ascii_player() {
+        let pgn = "[White \"Muñoz\"]\n[Black \"Søren\"]\n";
+        assert_eq!(
+            latin1(&[pgn.as_bytes()]),
+            b"[White \"Mu\xf1oz\"]\n[Black \"S\xf8ren\"]\n"
+        );
+    }
+
+    #[test]
+    fn latin1_character_split_across_writes() {
+        let bytes = "Muñoz".as_bytes();
+        // ñ is 0xC3 0xB1, split after its first byte.
+        assert_eq!(latin1(&[&bytes[..3], &bytes[3..]]), b"Mu\xf1oz");
+    }
+
+    #[test]
+    fn latin1_unencodable() {
+        assert_eq!(latin1(&["1-0 ♔".as_bytes()]), b"1-0 ?");
+        assert_eq!(latin1(&[b"a\xffb"]), b"a?b");
+    }
+}
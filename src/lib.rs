@@ -0,0 +1,2583 @@
+use bytes::Bytes;
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use futures::stream::StreamExt;
+use log::{error, info, log, warn, Level};
+use reqwest::header::{IF_MODIFIED_SINCE, LAST_MODIFIED};
+use reqwest::redirect::Policy;
+use reqwest::{Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+mod cli;
+pub use cli::Options;
+use cli::{Backoff, Concurrency, IpFamily, ParseErrorPolicy};
+
+mod types;
+use types::{
+    clock_times, game_id_from_link, san_moves, utc_now, Date, Game, GameResult, PGNMetadata, Time,
+};
+
+mod error;
+pub use error::DownloadError;
+
+mod parse;
+use parse::{par_parse_each, parse_each, parse_json_archive, ChessParser};
+
+mod format;
+use format::Format;
+
+mod source;
+pub use source::{ChessCom, GameSource};
+
+mod cache;
+use cache::Cache;
+
+/// Name used in place of the username when grouping users.
+const ALL_USERS: &str = "AllUsers";
+/// Year of the games without a date, with --by-year.
+const NO_YEAR: &str = "NoYear";
+/// Time given to a run to save its games after --hard-timeout-secs before it is killed.
+const HARD_TIMEOUT_GRACE: Duration = Duration::from_secs(30);
+/// Opening name of the games without one, with --group-by-opening-name.
+const UNKNOWN_OPENING: &str = "UnknownOpening";
+
+/// A month of a user's games.
+pub struct Archive {
+    username: String,
+    url: String,
+}
+impl Archive {
+    /// Year and month of the archive, from the end of its URL.
+    fn month(&self) -> Option<(i64, u32)> {
+        let mut parts = self.url.trim_end_matches("/pgn").rsplit('/');
+        let month = parts.next()?.parse().ok()?;
+        let year = parts.next()?.parse().ok()?;
+        Some((year, month))
+    }
+}
+pub type Archives = Vec<Archive>;
+
+struct PGNMessage {
+    username: String,
+    /// URL the games were downloaded from.
+    source_url: String,
+    /// Year and month of the archive, `None` for ongoing games.
+    month: Option<(i64, u32)>,
+    /// Last-Modified date of the archive, saved to --sync-state once its games are written.
+    last_modified: Option<String>,
+    bytes: Bytes,
+}
+
+/// Outcome of each archive download. Every archive is counted exactly once.
+#[derive(Default)]
+struct DownloadStats {
+    downloaded: AtomicUsize,
+    empty: AtomicUsize,
+    failed: AtomicUsize,
+    too_large: AtomicUsize,
+    past_deadline: AtomicUsize,
+    /// Not modified since the last run, with --sync-state.
+    unchanged: AtomicUsize,
+    /// Read from --cache-dir instead of downloaded.
+    cached: AtomicUsize,
+    /// Not downloaded because of too many empty archives of the same user before.
+    skipped: AtomicUsize,
+    cancelled: AtomicUsize,
+    /// Size of the downloaded archives.
+    bytes: AtomicU64,
+    /// Retries over all archives.
+    retries: AtomicUsize,
+    /// Attempts that failed before a response arrived, and those that failed while reading the
+    /// body of a response. Unlike the outcomes above, an archive can be counted several times.
+    request_failures: AtomicUsize,
+    body_failures: AtomicUsize,
+    max_attempts: AtomicUsize,
+    /// Archives that needed more than one attempt, with the number of attempts.
+    retried: Mutex<Vec<(String, u32)>>,
+    /// Archives that failed or ran past --archive-deadline-secs, for --report-json.
+    failed_urls: Mutex<Vec<String>>,
+}
+impl DownloadStats {
+    fn record_failure(&self, url: String) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        self.failed_urls.lock().unwrap().push(url);
+    }
+
+    fn counts(&self, total: usize) -> ArchiveCounts {
+        ArchiveCounts {
+            total,
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            empty: self.empty.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            too_large: self.too_large.load(Ordering::Relaxed),
+            past_deadline: self.past_deadline.load(Ordering::Relaxed),
+            unchanged: self.unchanged.load(Ordering::Relaxed),
+            cached: self.cached.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            cancelled: self.cancelled.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record_attempts(&self, url: String, attempts: u32) {
+        self.max_attempts
+            .fetch_max(attempts as usize, Ordering::Relaxed);
+        if attempts > 1 {
+            self.retried.lock().unwrap().push((url, attempts));
+        }
+    }
+}
+
+/// Caps the bytes read per second over all downloads with --max-bps by spacing out the chunks of
+/// their bodies.
+struct Throttle {
+    bytes_per_sec: u64,
+    /// When the bytes read so far are paid for.
+    next: Mutex<Instant>,
+}
+impl Throttle {
+    /// Waits until `len` more bytes can be read.
+    async fn consume(&self, len: usize) {
+        let ready = {
+            let mut next = self.next.lock().unwrap();
+            let start = (*next).max(Instant::now());
+            *next = start + Duration::from_secs_f64(len as f64 / self.bytes_per_sec as f64);
+            *next
+        };
+        tokio::time::sleep_until(ready).await;
+    }
+}
+
+/// Saves --sync-state with --checkpoint-every while the games are downloaded. The lock also keeps
+/// the writers from merging into the same output file at the same time.
+struct Checkpoint {
+    path: PathBuf,
+    every: usize,
+    /// The state of the previous run, updated with the archives whose games were merged.
+    state: Mutex<BTreeMap<String, String>>,
+}
+
+/// Inputs that failed to parse, written to --dump-unparsed.
+struct UnparsedDumps {
+    dir: PathBuf,
+    max: usize,
+    written: AtomicUsize,
+}
+impl UnparsedDumps {
+    /// Writes `input` from `source_url` with the error it failed with, unless --max-unparsed-dumps
+    /// inputs were written already.
+    fn dump(&self, source_url: &str, input: &str, error: &DownloadError) -> std::io::Result<()> {
+        let n = self.written.fetch_add(1, Ordering::Relaxed) + 1;
+        if n > self.max {
+            if n == self.max + 1 {
+                warn!(
+                    "Wrote {} inputs that failed to parse to {}, not writing more",
+                    self.max,
+                    self.dir.display()
+                );
+            }
+            return Ok(());
+        }
+        let path = self.dir.join(format!("unparsed_{:04}.txt", n));
+        std::fs::write(
+            path,
+            format!("Source: {}\nError: {}\n\n{}", source_url, error, input),
+        )
+    }
+}
+
+/// Stops the download once --max-new-games games were written.
+struct GameBudget {
+    max: Option<usize>,
+    taken: AtomicUsize,
+    stop: CancellationToken,
+}
+impl GameBudget {
+    /// Takes a game from the budget, or returns false if it is used up.
+    fn take(&self) -> bool {
+        let max = match self.max {
+            Some(max) => max,
+            None => return true,
+        };
+        let taken = self.taken.fetch_add(1, Ordering::Relaxed) + 1;
+        if taken >= max {
+            self.stop.cancel();
+        }
+        taken <= max
+    }
+}
+
+/// Random sample of the games that passed the filters, with --sample. Reservoir sampling keeps at
+/// most --sample games (per user with --sample-per-user) in memory until all archives are parsed.
+struct Sample {
+    size: usize,
+    per_user: bool,
+    reservoirs: Mutex<HashMap<String, Reservoir>>,
+}
+
+#[derive(Default)]
+struct Reservoir {
+    /// Games offered so far.
+    seen: usize,
+    games: Vec<(PGNMetadata, Game)>,
+}
+
+impl Sample {
+    fn offer(&self, username: &str, game_info: PGNMetadata, game: Game) {
+        let key = if self.per_user { username } else { "" };
+        let mut reservoirs = self.reservoirs.lock().unwrap();
+        let reservoir = reservoirs.entry(key.to_owned()).or_default();
+        reservoir.seen += 1;
+        if reservoir.games.len() < self.size {
+            reservoir.games.push((game_info, game));
+        } else {
+            // Each of the games seen so far ends up in the sample with the same probability.
+            let i = fastrand::usize(..reservoir.seen);
+            if i < self.size {
+                reservoir.games[i] = (game_info, game);
+            }
+        }
+    }
+
+    /// The sampled games from oldest to newest.
+    fn take_games(&self) -> Vec<(PGNMetadata, Game)> {
+        let reservoirs = std::mem::take(&mut *self.reservoirs.lock().unwrap());
+        let mut games = reservoirs
+            .into_values()
+            .flat_map(|reservoir| reservoir.games)
+            .collect::<Vec<_>>();
+        games.sort_by_key(|(_, game)| game.timestamp);
+        games
+    }
+}
+
+/// Games seen by the writer threads.
+#[derive(Default)]
+struct ParseStats {
+    /// Games that failed to parse.
+    failed: usize,
+    not_played: usize,
+    /// Games skipped with --dedupe-global because another user's archive had them already.
+    duplicates: usize,
+    /// Games skipped with --dedupe-by-moves because a game with the same moves was written.
+    same_moves: usize,
+    /// Games that didn't parse back into the same game with --verify.
+    unverified: usize,
+    /// Files written with --split-games.
+    game_files: usize,
+    users: HashMap<String, UserGames>,
+    /// Names of the users as written in the PGN, with --normalize-usernames-from-pgn.
+    display_names: HashMap<String, String>,
+    /// Record of each user against each opponent, with --stats-per-opponent.
+    opponents: BTreeMap<String, BTreeMap<String, JSONRecord>>,
+    /// Last-Modified date of the archives that were processed, for --sync-state.
+    synced: BTreeMap<String, String>,
+    /// Games and bytes merged into each output file by --checkpoint-every.
+    checkpointed: HashMap<PGNMetadata, (usize, u64)>,
+    /// Games written with white and with black by time class.
+    colors: BTreeMap<String, [usize; 2]>,
+}
+impl ParseStats {
+    fn merge(&mut self, other: ParseStats) {
+        self.failed += other.failed;
+        self.not_played += other.not_played;
+        self.game_files += other.game_files;
+        self.duplicates += other.duplicates;
+        self.same_moves += other.same_moves;
+        self.unverified += other.unverified;
+        for (username, name) in other.display_names {
+            self.display_names.entry(username).or_insert(name);
+        }
+        for (username, games) in other.users {
+            let user = self.users.entry(username).or_default();
+            user.parsed += games.parsed;
+            user.written += games.written;
+            user.first = user.first.into_iter().chain(games.first).min();
+            user.last = user.last.into_iter().chain(games.last).max();
+        }
+        self.synced.extend(other.synced);
+        for (game_info, (games, bytes)) in other.checkpointed {
+            let total = self.checkpointed.entry(game_info).or_default();
+            total.0 += games;
+            total.1 += bytes;
+        }
+        for (username, opponents) in other.opponents {
+            let user = self.opponents.entry(username).or_default();
+            for (opponent, record) in opponents {
+                let total = user.entry(opponent).or_default();
+                total.win += record.win;
+                total.loss += record.loss;
+                total.draw += record.draw;
+            }
+        }
+        for (time, [white, black]) in other.colors {
+            let colors = self.colors.entry(time).or_default();
+            colors[0] += white;
+            colors[1] += black;
+        }
+    }
+
+    fn parsed(&self) -> usize {
+        self.users.values().map(|games| games.parsed).sum()
+    }
+
+    /// Percentage of the games that failed to parse.
+    fn error_rate(&self) -> f64 {
+        let total = self.parsed() + self.failed;
+        if total == 0 {
+            0.0
+        } else {
+            self.failed as f64 * 100.0 / total as f64
+        }
+    }
+}
+
+#[derive(Default)]
+struct UserGames {
+    parsed: usize,
+    written: usize,
+    /// Dates of the oldest and newest games parsed.
+    first: Option<Date>,
+    last: Option<Date>,
+}
+
+/// Temporary file holding the games of one output group.
+struct GroupFile {
+    file: File,
+    games: usize,
+}
+
+#[derive(Serialize)]
+struct ManifestEntry {
+    file: String,
+    username: String,
+    color: String,
+    variant: Option<String>,
+    time: String,
+    /// Unknown in raw mode.
+    games: Option<usize>,
+    bytes: u64,
+}
+
+/// Games selected by --include-ids and --exclude-ids.
+struct GameIds {
+    include: Option<HashSet<u64>>,
+    exclude: HashSet<u64>,
+    /// Games written so far, for --dedupe-global.
+    seen: Mutex<HashSet<u64>>,
+    /// Move fingerprints of the games written so far, for --dedupe-by-moves.
+    seen_moves: Mutex<HashSet<u64>>,
+}
+impl GameIds {
+    fn load(opt: &Options) -> Result<GameIds, DownloadError> {
+        Ok(GameIds {
+            include: opt.include_ids.as_deref().map(read_game_ids).transpose()?,
+            exclude: match &opt.exclude_ids {
+                Some(path) => read_game_ids(path)?,
+                None => HashSet::new(),
+            },
+            seen: Mutex::new(HashSet::new()),
+            seen_moves: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Whether no game with this ID was seen before by any writer. Games without an ID are
+    /// never considered duplicates.
+    fn first_occurrence(&self, id: Option<u64>) -> bool {
+        match id {
+            Some(id) => self.seen.lock().unwrap().insert(id),
+            None => true,
+        }
+    }
+
+    /// Whether no game with these moves was seen before by any writer. Games without moves are
+    /// never considered duplicates.
+    fn first_moves(&self, fingerprint: Option<u64>) -> bool {
+        match fingerprint {
+            Some(fingerprint) => self.seen_moves.lock().unwrap().insert(fingerprint),
+            None => true,
+        }
+    }
+
+    /// Games without an ID are only kept if no games are explicitly included.
+    fn allows(&self, id: Option<u64>) -> bool {
+        match id {
+            Some(id) => {
+                !self.exclude.contains(&id) && self.include.iter().all(|ids| ids.contains(&id))
+            }
+            None => self.include.is_none(),
+        }
+    }
+}
+
+/// Reads one game ID or link per line, ignoring blank lines and lines starting with `#`.
+fn read_game_ids(path: &Path) -> Result<HashSet<u64>, DownloadError> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.parse()
+                .ok()
+                .or_else(|| game_id_from_link(line))
+                .ok_or_else(|| DownloadError::InvalidGameId {
+                    path: path.to_owned(),
+                    line: line.to_owned(),
+                })
+        })
+        .collect()
+}
+
+/// Reads one `handle=canonical` pair per line, ignoring blank lines and lines starting with `#`.
+/// Both names are lowercased like the usernames.
+fn read_aliases(path: &Path) -> Result<HashMap<String, String>, DownloadError> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once('=') {
+            Some((handle, canonical))
+                if !handle.trim().is_empty() && !canonical.trim().is_empty() =>
+            {
+                Ok((
+                    handle.trim().to_lowercase(),
+                    canonical.trim().to_lowercase(),
+                ))
+            }
+            _ => Err(DownloadError::InvalidAlias {
+                path: path.to_owned(),
+                line: line.to_owned(),
+            }),
+        })
+        .collect()
+}
+
+/// Ratings and records of a player by time class. Time classes the player never played are missing.
+#[derive(Deserialize, Debug)]
+struct JSONStats {
+    chess_bullet: Option<JSONTimeClassStats>,
+    chess_blitz: Option<JSONTimeClassStats>,
+    chess_rapid: Option<JSONTimeClassStats>,
+    chess_daily: Option<JSONTimeClassStats>,
+}
+
+impl JSONStats {
+    /// The stats of the time classes the player played.
+    fn by_time(self) -> Vec<(Time, JSONTimeClassStats)> {
+        vec![
+            (Time::Bullet, self.chess_bullet),
+            (Time::Blitz, self.chess_blitz),
+            (Time::Rapid, self.chess_rapid),
+            (Time::Daily, self.chess_daily),
+        ]
+        .into_iter()
+        .filter_map(|(time, stats)| Some((time, stats?)))
+        .collect()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct JSONTimeClassStats {
+    last: Option<JSONRating>,
+    best: Option<JSONRating>,
+    record: JSONRecord,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct JSONRating {
+    rating: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct JSONRecord {
+    win: usize,
+    loss: usize,
+    draw: usize,
+}
+
+/// The `failed` list of a --report-json written after downloading the games.
+#[derive(Deserialize)]
+struct FailedArchives {
+    failed: Vec<String>,
+}
+
+/// The archives that failed in the run that wrote the --report-json at `path`.
+fn read_failed_archives<S: GameSource>(path: &Path, source: &S) -> Result<Archives, DownloadError> {
+    let report: FailedArchives = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    report
+        .failed
+        .into_iter()
+        .map(|url| match source.username_of(&url) {
+            Some(username) => Ok(Archive { username, url }),
+            None => Err(DownloadError::UnknownArchiveUser(url)),
+        })
+        .collect()
+}
+
+/// Number of archives with each outcome, which add up to `total`.
+#[derive(Serialize)]
+struct ArchiveCounts {
+    total: usize,
+    downloaded: usize,
+    empty: usize,
+    failed: usize,
+    too_large: usize,
+    past_deadline: usize,
+    unchanged: usize,
+    cached: usize,
+    skipped: usize,
+    cancelled: usize,
+}
+
+/// Written to --report-json after downloading the games.
+#[derive(Serialize)]
+struct DownloadReport<'a> {
+    archives: ArchiveCounts,
+    /// URLs of the archives that failed, for --retry-from-report.
+    failed: &'a [String],
+    /// Record of each user against each opponent, only with --stats-per-opponent.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    opponents: &'a BTreeMap<String, BTreeMap<String, JSONRecord>>,
+}
+
+/// Daily games a player is currently in.
+#[derive(Deserialize, Debug)]
+struct JSONOngoingGames {
+    games: Vec<JSONOngoingGame>,
+}
+
+#[derive(Deserialize, Debug)]
+struct JSONOngoingGame {
+    pgn: Option<String>,
+}
+
+/// Runs chess_dl with the options from the command line until it is done or interrupted.
+pub async fn run(mut options: Options) -> Result<(), Box<dyn Error>> {
+    options.usernames = options
+        .usernames
+        .into_iter()
+        .map(|u| u.to_lowercase())
+        .collect::<Vec<String>>();
+    let excluded = options
+        .exclude_user
+        .iter()
+        .map(|u| u.to_lowercase())
+        .collect::<HashSet<String>>();
+    options.usernames.retain(|u| !excluded.contains(u));
+    options.apply_presets();
+    if options.concurrent == Concurrency::Auto {
+        let concurrent = options.concurrent.resolve();
+        info!("Using {} concurrent downloads", concurrent);
+        options.concurrent = Concurrency::Fixed(concurrent);
+    }
+
+    let token = CancellationToken::new();
+    if let Some(secs) = options.hard_timeout_secs {
+        // A thread of its own, so that it fires even if the runtime or the writer is stuck.
+        let token = token.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(secs));
+            warn!(
+                "Exceeded the hard timeout of {} seconds, saving the games downloaded so far...",
+                secs
+            );
+            token.cancel();
+            std::thread::sleep(HARD_TIMEOUT_GRACE);
+            error!(
+                "Still running {} seconds after the hard timeout, exiting. The output may be incomplete",
+                HARD_TIMEOUT_GRACE.as_secs()
+            );
+            std::process::exit(2);
+        });
+    }
+
+    tokio::spawn({
+        let token = token.clone();
+        async move {
+            if shutdown_signal().await.is_ok() {
+                warn!("Interrupted, saving the games downloaded so far...");
+                token.cancel();
+            }
+        }
+    });
+    let (pause, paused) = watch::channel(false);
+    tokio::spawn(pause_on_signals(pause));
+
+    let chess_com = ChessCom::new(&options.api_base);
+    if options.list_archives {
+        list_archives(&options, &chess_com, &token).await?;
+    } else if options.probe {
+        probe(&options, &chess_com, &token).await?;
+    } else if options.stats {
+        print_stats(&options, &chess_com, &token).await?;
+    } else {
+        if let Some(min) = options.min_games {
+            options.usernames = users_with_min_games(&options, &chess_com, &token, min).await?;
+        }
+        loop {
+            match download_all_games(&options, &chess_com, &token, paused.clone()).await {
+                Err(e) if options.follow && !token.is_cancelled() => {
+                    error!("Sync failed, trying again in the next one: {}", e)
+                }
+                result => result?,
+            }
+            if !options.follow {
+                break;
+            }
+            info!("Syncing again in {} minutes", options.interval);
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = tokio::time::sleep(Duration::from_secs(options.interval * 60)) => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resolves on Ctrl+C, or when the process is asked to terminate.
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        r = tokio::signal::ctrl_c() => r,
+        _ = sigterm.recv() => Ok(()),
+    }
+}
+
+/// Resolves on Ctrl+C.
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+/// Pauses the downloads on SIGUSR1 and resumes them on SIGUSR2. Downloads that already started
+/// finish, but no new requests are made while paused.
+#[cfg(unix)]
+async fn pause_on_signals(pause: watch::Sender<bool>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let (mut sigusr1, mut sigusr2) = match (
+        signal(SignalKind::user_defined1()),
+        signal(SignalKind::user_defined2()),
+    ) {
+        (Ok(sigusr1), Ok(sigusr2)) => (sigusr1, sigusr2),
+        _ => return,
+    };
+    loop {
+        let paused = tokio::select! {
+            _ = sigusr1.recv() => true,
+            _ = sigusr2.recv() => false,
+        };
+        if pause.send_replace(paused) != paused {
+            if paused {
+                warn!("Paused, send SIGUSR2 to resume");
+            } else {
+                warn!("Resumed");
+            }
+        }
+    }
+}
+
+/// Downloads can't be paused without signals.
+#[cfg(not(unix))]
+async fn pause_on_signals(_pause: watch::Sender<bool>) {}
+
+fn build_client(opt: &Options) -> reqwest::Result<Client> {
+    // Compressed responses, e.g. from a proxy, are decoded before they reach the parser.
+    let mut builder = Client::builder()
+        .gzip(true)
+        .brotli(true)
+        .pool_idle_timeout(Duration::from_secs(opt.pool_idle_timeout))
+        .redirect(redirect_policy(opt.max_redirects));
+    if opt.http2 {
+        builder = builder.http2_prior_knowledge();
+    }
+    // With a local address of one family, hyper's connector drops the resolved addresses of the
+    // other family before connecting, so only that family is used.
+    builder = match opt.ip_family {
+        IpFamily::Auto => builder,
+        IpFamily::V4 => builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        IpFamily::V6 => builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+    };
+    builder.build()
+}
+
+/// Follows up to `max` redirects, e.g. when archives move to another host. Going back to a URL that
+/// was already visited fails the request instead of following the loop until the limit.
+fn redirect_policy(max: usize) -> Policy {
+    Policy::custom(move |attempt| {
+        if attempt.previous().len() > max {
+            attempt.error("too many redirects")
+        } else if attempt.previous().contains(attempt.url()) {
+            attempt.error("redirect loop")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// Lists the archives of all users, `concurrency` users at a time. The archives are in the order of
+/// `usernames`.
+async fn fetch_archives<S: GameSource>(
+    source: &S,
+    client: &Client,
+    usernames: &[String],
+    concurrency: usize,
+) -> Result<Archives, DownloadError> {
+    let user_archives = futures::stream::iter(
+        usernames
+            .iter()
+            .map(|username| source.archives(client, username)),
+    )
+    .buffered(concurrency)
+    .collect::<Vec<Result<Archives, DownloadError>>>()
+    .await;
+
+    let mut archives = Archives::new();
+    for user_archives in user_archives {
+        archives.append(&mut user_archives?);
+    }
+    Ok(archives)
+}
+
+/// Lists the archives of all users like `fetch_archives`. With --cache-dir the lists are saved to
+/// the cache, and with --offline they are read from it instead. With --months they aren't listed
+/// at all.
+async fn list_user_archives<S: GameSource>(
+    opt: &Options,
+    source: &S,
+    client: &Client,
+    cache: Option<&Cache>,
+) -> Result<Archives, DownloadError> {
+    if !opt.months.is_empty() {
+        let months = expand_months(&opt.months, Date::today().year_month());
+        return Ok(opt
+            .usernames
+            .iter()
+            .flat_map(|username| {
+                months.iter().map(move |&month| Archive {
+                    username: username.clone(),
+                    url: source.month_url(username, month),
+                })
+            })
+            .collect());
+    }
+    let cache_key = |username: &str| format!("archives/{}", username);
+    match cache {
+        Some(cache) if opt.offline => {
+            let mut archives = Archives::new();
+            for username in &opt.usernames {
+                let key = cache_key(username);
+                let bytes = cache.get(&key).ok_or(DownloadError::NotCached(key))?;
+                let urls: Vec<String> = serde_json::from_slice(&bytes)?;
+                archives.extend(urls.into_iter().map(|url| Archive {
+                    username: username.clone(),
+                    url,
+                }));
+            }
+            Ok(archives)
+        }
+        _ => {
+            let archives =
+                fetch_archives(source, client, &opt.usernames, opt.list_concurrency).await?;
+            if let Some(cache) = cache {
+                for username in &opt.usernames {
+                    let urls = archives
+                        .iter()
+                        .filter(|archive| archive.username == *username)
+                        .map(|archive| archive.url.as_str())
+                        .collect::<Vec<_>>();
+                    cache.put(&cache_key(username), &serde_json::to_vec(&urls)?);
+                }
+            }
+            Ok(archives)
+        }
+    }
+}
+
+/// The months matching --months patterns, in order. Each pattern is a month (`2023/05`), all months
+/// of a year (`2023/*`) or a range of months (`2022/11-2023/02`). Invalid patterns and months after
+/// `current` are skipped with a warning.
+fn expand_months(patterns: &[String], current: (i64, u32)) -> Vec<(i64, u32)> {
+    let parse_month = |month: &str| -> Option<(i64, u32)> {
+        let (year, month) = month.trim().split_once('/')?;
+        let month = month
+            .parse()
+            .ok()
+            .filter(|month| (1..=12).contains(month))?;
+        Some((year.parse().ok()?, month))
+    };
+    let mut months = BTreeSet::new();
+    for pattern in patterns {
+        let range = match pattern.trim().split_once('-') {
+            Some((first, last)) => parse_month(first).zip(parse_month(last)),
+            None => match pattern.trim().strip_suffix("/*") {
+                Some(year) => year.parse().ok().map(|year| ((year, 1), (year, 12))),
+                None => parse_month(pattern).map(|month| (month, month)),
+            },
+        };
+        let (mut month, last) = match range {
+            Some(range) => range,
+            None => {
+                warn!(
+                    "Skipping invalid month {:?}, expected e.g. 2023/05",
+                    pattern
+                );
+                continue;
+            }
+        };
+        while month <= last {
+            if month > current {
+                // Whole years are expected to reach into the future.
+                if !pattern.ends_with('*') {
+                    warn!("Skipping {}/{:02}, it is in the future", month.0, month.1);
+                }
+                break;
+            }
+            months.insert(month);
+            month = if month.1 == 12 {
+                (month.0 + 1, 1)
+            } else {
+                (month.0, month.1 + 1)
+            };
+        }
+    }
+    months.into_iter().collect()
+}
+
+/// Takes one archive of each user in turn, keeping the order of each user's archives, so that a
+/// run that stops early has archives of every user.
+fn interleave_users(archives: Archives) -> Archives {
+    let mut users = Vec::<VecDeque<Archive>>::new();
+    for archive in archives {
+        match users
+            .iter_mut()
+            .find(|user| user[0].username == archive.username)
+        {
+            Some(user) => user.push_back(archive),
+            None => users.push(VecDeque::from(vec![archive])),
+        }
+    }
+    let mut interleaved = Archives::new();
+    while !users.is_empty() {
+        users.retain_mut(|user| match user.pop_front() {
+            Some(archive) => {
+                interleaved.push(archive);
+                true
+            }
+            None => false,
+        });
+    }
+    interleaved
+}
+
+/// Whether the month of `archive` is within --from and --to. Archives without a month are kept.
+fn archive_in_range(opt: &Options, archive: &Archive) -> bool {
+    let month = match archive.month() {
+        Some(month) => month,
+        None => return true,
+    };
+    opt.from.iter().all(|from| month >= (from.year, from.month))
+        && opt.to.iter().all(|to| month <= (to.year, to.month))
+}
+
+async fn list_archives<S: GameSource>(
+    opt: &Options,
+    source: &S,
+    token: &CancellationToken,
+) -> Result<(), DownloadError> {
+    let client = build_client(opt)?;
+    let mut archives = tokio::select! {
+        _ = token.cancelled() => return Err(DownloadError::Cancelled),
+        archives = fetch_archives(source, &client, &opt.usernames, opt.list_concurrency) => archives?,
+    };
+
+    archives.retain(|archive| archive_in_range(opt, archive));
+    for archive in &archives {
+        println!("{}", archive.url);
+    }
+    if let Some(report_path) = &opt.report_json {
+        let mut report = BTreeMap::<&str, Vec<&str>>::new();
+        for archive in &archives {
+            report
+                .entry(&archive.username)
+                .or_default()
+                .push(&archive.url);
+        }
+        serde_json::to_writer_pretty(File::create(report_path)?, &report)?;
+    }
+    Ok(())
+}
+
+async fn fetch_stats(
+    chess_com: &ChessCom,
+    client: &Client,
+    username: &str,
+) -> reqwest::Result<JSONStats> {
+    let url = format!("{}/stats", chess_com.player_url(username));
+    client.get(url).send().await?.json::<JSONStats>().await
+}
+
+/// The users with at least `min` rated games according to their chess.com stats. Users whose
+/// stats can't be fetched are kept.
+async fn users_with_min_games(
+    opt: &Options,
+    chess_com: &ChessCom,
+    token: &CancellationToken,
+    min: usize,
+) -> Result<Vec<String>, DownloadError> {
+    let client = build_client(opt)?;
+    let user_stats = futures::stream::iter(
+        opt.usernames
+            .iter()
+            .map(|username| fetch_stats(chess_com, &client, username)),
+    )
+    .buffered(opt.list_concurrency)
+    .collect::<Vec<_>>();
+    let user_stats = tokio::select! {
+        _ = token.cancelled() => return Err(DownloadError::Cancelled),
+        user_stats = user_stats => user_stats,
+    };
+    let mut usernames = Vec::new();
+    for (username, stats) in opt.usernames.iter().zip(user_stats) {
+        let games = match stats {
+            Ok(stats) => stats
+                .by_time()
+                .into_iter()
+                .map(|(_, stats)| stats.record.win + stats.record.loss + stats.record.draw)
+                .sum::<usize>(),
+            Err(e) => {
+                warn!("Can't check the number of games of {}: {}", username, e);
+                usernames.push(username.clone());
+                continue;
+            }
+        };
+        if games < min {
+            warn!(
+                "Skipping {}, they have {} rated games, fewer than {}",
+                username, games, min
+            );
+        } else {
+            usernames.push(username.clone());
+        }
+    }
+    Ok(usernames)
+}
+
+/// Prints the number of games of each user by time class, according to their chess.com stats.
+async fn probe(
+    opt: &Options,
+    chess_com: &ChessCom,
+    token: &CancellationToken,
+) -> Result<(), DownloadError> {
+    let client = build_client(opt)?;
+    let mut report = BTreeMap::<&str, BTreeMap<String, usize>>::new();
+    for username in &opt.usernames {
+        let stats = tokio::select! {
+            _ = token.cancelled() => return Err(DownloadError::Cancelled),
+            stats = fetch_stats(chess_com, &client, username) => stats?,
+        };
+        let counts = stats
+            .by_time()
+            .into_iter()
+            .map(|(time, stats)| {
+                let record = stats.record;
+                (time.to_string(), record.win + record.loss + record.draw)
+            })
+            .collect::<Vec<_>>();
+        println!(
+            "{}: {}",
+            username,
+            counts
+                .iter()
+                .map(|(time, games)| format!("{} {}", games, time))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        report.insert(username, counts.into_iter().collect());
+    }
+    if let Some(report_path) = &opt.report_json {
+        serde_json::to_writer_pretty(File::create(report_path)?, &report)?;
+    }
+    Ok(())
+}
+
+/// Prints the current and best rating and the record of each user by time class.
+async fn print_stats(
+    opt: &Options,
+    chess_com: &ChessCom,
+    token: &CancellationToken,
+) -> Result<(), DownloadError> {
+    let client = build_client(opt)?;
+    let mut report = BTreeMap::<&str, BTreeMap<String, JSONTimeClassStats>>::new();
+    for username in &opt.usernames {
+        let stats = tokio::select! {
+            _ = token.cancelled() => return Err(DownloadError::Cancelled),
+            stats = fetch_stats(chess_com, &client, username) => stats?,
+        };
+        println!("{}:", username);
+        let rating = |rating: &Option<JSONRating>| match rating {
+            Some(rating) => rating.rating.to_string(),
+            None => "-".to_owned(),
+        };
+        let stats = stats.by_time();
+        for (time, stats) in &stats {
+            println!(
+                "  {}: rating {} (best {}), {} wins, {} losses, {} draws",
+                time,
+                rating(&stats.last),
+                rating(&stats.best),
+                stats.record.win,
+                stats.record.loss,
+                stats.record.draw
+            );
+        }
+        report.insert(
+            username,
+            stats
+                .into_iter()
+                .map(|(time, stats)| (time.to_string(), stats))
+                .collect(),
+        );
+    }
+    if let Some(report_path) = &opt.report_json {
+        serde_json::to_writer_pretty(File::create(report_path)?, &report)?;
+    }
+    Ok(())
+}
+
+/// Downloads the games of `opt.usernames` from `source` and writes them to the output files. The
+/// downloads stop when `token` is cancelled, and new ones wait while `paused` is true.
+pub async fn download_all_games<S: GameSource>(
+    opt: &Options,
+    source: &S,
+    token: &CancellationToken,
+    paused: watch::Receiver<bool>,
+) -> Result<(), DownloadError> {
+    let game_ids = Arc::new(GameIds::load(opt)?);
+    let aliases = Arc::new(match &opt.aliases {
+        Some(path) => read_aliases(path)?,
+        None => HashMap::new(),
+    });
+    if opt.writes_files() {
+        // Fail before downloading anything rather than when writing the files.
+        let dir = opt.destination_dir();
+        if opt.no_create_dir {
+            if !dir.is_dir() {
+                return Err(DownloadError::MissingOutputDir(dir.to_owned()));
+            }
+        } else {
+            std::fs::create_dir_all(dir)?;
+        }
+        if let Some(min) = opt.min_free_space {
+            match fs2::available_space(dir) {
+                Ok(free) if free < min => {
+                    return Err(DownloadError::NotEnoughSpace {
+                        dir: dir.to_owned(),
+                        free,
+                        min,
+                    })
+                }
+                Ok(_) => (),
+                // Not every platform can tell, so this isn't a reason to stop.
+                Err(e) => warn!("Can't check the free space in {}: {}", dir.display(), e),
+            }
+        }
+    }
+    let client = build_client(opt)?;
+    let cache = opt.cache_dir.as_deref().map(Cache::new).transpose()?;
+    let archives = match &opt.retry_from_report {
+        // Already filtered and converted to PGN URLs when they failed.
+        Some(report_path) => read_failed_archives(report_path, source)?,
+        None => {
+            let mut archives = tokio::select! {
+                _ = token.cancelled() => return Err(DownloadError::Cancelled),
+                archives = list_user_archives(opt, source, &client, cache.as_ref()) => archives?,
+            };
+            archives.retain(|archive| archive_in_range(opt, archive));
+            if opt.newest_first {
+                archives.sort_by_key(|archive| std::cmp::Reverse(archive.month()));
+            }
+            if opt.fair_ordering {
+                archives = interleave_users(archives);
+            }
+            if !opt.json_games {
+                for archive in &mut archives {
+                    archive.url = source.pgn_url(archive);
+                }
+            }
+            archives
+        }
+    };
+
+    let num_archives = archives.len();
+    info!("Found {} archives to download", num_archives);
+
+    // Cancelled on interruption, or once enough games or bytes were downloaded.
+    let stop = token.child_token();
+    let budget = Arc::new(GameBudget {
+        max: opt.max_new_games,
+        taken: AtomicUsize::new(0),
+        stop: stop.clone(),
+    });
+    let last_modified = Mutex::new(match &opt.sync_state {
+        Some(path) => read_sync_state(path)?,
+        None => BTreeMap::new(),
+    });
+    let checkpoint = match (&opt.sync_state, opt.checkpoint_every) {
+        (Some(path), Some(every)) => Some(Arc::new(Checkpoint {
+            path: path.clone(),
+            every,
+            state: Mutex::new(last_modified.lock().unwrap().clone()),
+        })),
+        _ => None,
+    };
+    let (send, rec) = unbounded::<PGNMessage>();
+    let opt_cp = opt.clone();
+    let write_worker = std::thread::spawn(move || {
+        process_pgn_messages(&opt_cp, game_ids, aliases, budget, checkpoint, rec)
+    });
+    let stats = DownloadStats::default();
+    let archive_delay = opt.archive_delay_ms.map(Duration::from_millis);
+    let throttle = opt.max_bps.map(|bytes_per_sec| Throttle {
+        bytes_per_sec,
+        next: Mutex::new(Instant::now()),
+    });
+    let next_start = Mutex::new(Instant::now());
+    // Empty archives in a row by user, with --max-empty-archives-before-stop. Archives finish in
+    // any order, so this is the order they were downloaded in rather than their months.
+    let empty_runs = Mutex::new(HashMap::<String, usize>::new());
+    let fetches = futures::stream::iter(archives.into_iter().map(|archive| {
+        let client = &client;
+        let stats = &stats;
+        let next_start = &next_start;
+        let stop = &stop;
+        let last_modified = &last_modified;
+        let cache = cache.as_ref();
+        let throttle = throttle.as_ref();
+        let empty_runs = &empty_runs;
+        let paused = paused.clone();
+        let send = send.clone();
+        async move {
+            let download = async {
+                if let Some(delay) = archive_delay {
+                    // Space out the start of each archive by at least `delay`.
+                    let start = {
+                        let mut next_start = next_start.lock().unwrap();
+                        let start = (*next_start).max(Instant::now());
+                        *next_start = start + delay;
+                        start
+                    };
+                    tokio::time::sleep_until(start).await;
+                }
+                if let Some(max) = opt.max_empty_archives_before_stop {
+                    let empty_run = empty_runs.lock().unwrap().get(&archive.username).copied();
+                    if empty_run.is_some_and(|run| run >= max) {
+                        stats.skipped.fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                }
+                let url = archive.url.clone();
+                let refresh = opt.refresh && archive.month() == Some(Date::today().year_month());
+                let cached = match cache {
+                    Some(cache) if !refresh => cache.get(&url),
+                    _ => None,
+                };
+                if let Some(bytes) = cached {
+                    log!(
+                        opt.progress_level(),
+                        "Read {} bytes of {} from the cache",
+                        bytes.len(),
+                        url
+                    );
+                    stats.cached.fetch_add(1, Ordering::Relaxed);
+                    let message = PGNMessage {
+                        month: archive.month(),
+                        username: archive.username,
+                        source_url: archive.url,
+                        last_modified: None,
+                        bytes,
+                    };
+                    send_to_writer(&send, stop, message);
+                    return;
+                }
+                if opt.offline {
+                    error!("{} is not cached", url);
+                    stats.record_failure(url);
+                    return;
+                }
+                let download_archive = download_archive(opt, client, archive, stats, last_modified, throttle, paused);
+                let attempts = match opt.archive_deadline_secs {
+                    Some(secs) => {
+                        let deadline = Duration::from_secs(secs);
+                        match tokio::time::timeout(deadline, download_archive).await {
+                            Ok(downloaded) => Some(downloaded),
+                            Err(_) => {
+                                error!("Gave up on {} after {} seconds", url, secs);
+                                stats.past_deadline.fetch_add(1, Ordering::Relaxed);
+                                stats.failed_urls.lock().unwrap().push(url.clone());
+                                None
+                            }
+                        }
+                    }
+                    None => Some(download_archive.await),
+                };
+                if let Some((attempts, message)) = attempts {
+                    stats.record_attempts(url, attempts);
+                    if let Some(message) = message {
+                        if let Some(max) = opt.max_empty_archives_before_stop {
+                            let mut empty_runs = empty_runs.lock().unwrap();
+                            let run = empty_runs.entry(message.username.clone()).or_default();
+                            if message.bytes.is_empty() {
+                                *run += 1;
+                                if *run == max {
+                                    warn!(
+                                        "{} empty archives in a row for {}, skipping the rest of their archives",
+                                        max, message.username
+                                    );
+                                }
+                            } else {
+                                *run = 0;
+                            }
+                        }
+                        if let (Some(cache), false) = (cache, message.bytes.is_empty()) {
+                            cache.put(&message.source_url, &message.bytes);
+                        }
+                        send_to_writer(&send, stop, message);
+                    }
+                }
+                if let Some(max_total_bytes) = opt.max_total_bytes {
+                    if stats.bytes.load(Ordering::Relaxed) >= max_total_bytes
+                        && !stop.is_cancelled()
+                    {
+                        warn!("Downloaded {} bytes, stopping", max_total_bytes);
+                        stop.cancel();
+                    }
+                }
+            };
+            tokio::select! {
+                _ = stop.cancelled() => {
+                    stats.cancelled.fetch_add(1, Ordering::Relaxed);
+                }
+                _ = download => {}
+            }
+        }
+    }))
+    .buffer_unordered(opt.concurrent.resolve())
+    .collect::<Vec<()>>();
+    fetches.await;
+    if opt.include_ongoing {
+        for username in &opt.usernames {
+            let url = match source.ongoing_games_url(username) {
+                Some(url) => url,
+                None => break,
+            };
+            let ongoing = tokio::select! {
+                _ = stop.cancelled() => break,
+                ongoing = fetch_ongoing_games(&client, &url) => ongoing,
+            };
+            match ongoing {
+                Ok((games, pgn)) => {
+                    info!("Downloaded {} ongoing games of {}", games, username);
+                    send_to_writer(
+                        &send,
+                        &stop,
+                        PGNMessage {
+                            username: username.clone(),
+                            source_url: url,
+                            month: None,
+                            last_modified: None,
+                            bytes: Bytes::from(pgn),
+                        },
+                    );
+                }
+                Err(e) => error!(
+                    "Failed to download the ongoing games of {}: {}",
+                    username, e
+                ),
+            }
+        }
+    }
+    drop(send);
+    // The summary is still printed if writing failed, to show how far the downloads got.
+    let (mut parse_stats, write_error) = match write_worker
+        .join()
+        .map_err(|_| DownloadError::WriterPanicked)
+        .and_then(|written| written)
+    {
+        Ok(parse_stats) => (parse_stats, None),
+        Err(e) => {
+            error!("Failed to write the games: {}", e);
+            (ParseStats::default(), Some(e))
+        }
+    };
+    if let (Some(path), None) = (&opt.sync_state, &write_error) {
+        // Only saved once the games were merged, so that failed runs download them again.
+        let mut state = last_modified.into_inner().unwrap();
+        state.extend(std::mem::take(&mut parse_stats.synced));
+        write_sync_state(path, &state)?;
+    }
+
+    // Printed directly with --summary-only so that it isn't hidden by the log level.
+    macro_rules! summary {
+        ($($arg:tt)*) => {
+            if opt.summary_only {
+                println!($($arg)*);
+            } else {
+                info!($($arg)*);
+            }
+        };
+    }
+    summary!("--- Download Summary ---");
+    summary!("Archives: {}", num_archives);
+    summary!("Downloaded: {}", stats.downloaded.load(Ordering::Relaxed));
+    match opt.max_total_bytes {
+        Some(max_total_bytes) => summary!(
+            "Bytes downloaded: {} of {} allowed",
+            stats.bytes.load(Ordering::Relaxed),
+            max_total_bytes
+        ),
+        None => summary!("Bytes downloaded: {}", stats.bytes.load(Ordering::Relaxed)),
+    }
+    summary!("Empty: {}", stats.empty.load(Ordering::Relaxed));
+    if opt.max_empty_archives_before_stop.is_some() {
+        summary!(
+            "Skipped after empty archives: {}",
+            stats.skipped.load(Ordering::Relaxed)
+        );
+    }
+    if opt.cache_dir.is_some() {
+        summary!("From the cache: {}", stats.cached.load(Ordering::Relaxed));
+    }
+    summary!("Failed: {}", stats.failed.load(Ordering::Relaxed));
+    let mut failed_urls = std::mem::take(&mut *stats.failed_urls.lock().unwrap());
+    failed_urls.sort();
+    if opt.retry_from_report.is_some() {
+        let succeeded = stats.downloaded.load(Ordering::Relaxed)
+            + stats.empty.load(Ordering::Relaxed)
+            + stats.cached.load(Ordering::Relaxed)
+            + stats.unchanged.load(Ordering::Relaxed);
+        summary!(
+            "Previously failed archives that succeeded: {} of {}",
+            succeeded,
+            num_archives
+        );
+    }
+    if opt.sync_state.is_some() {
+        summary!("Unchanged: {}", stats.unchanged.load(Ordering::Relaxed));
+    }
+    summary!("Retries: {}", stats.retries.load(Ordering::Relaxed));
+    summary!(
+        "Failed requests: {}",
+        stats.request_failures.load(Ordering::Relaxed)
+    );
+    summary!(
+        "Failed body reads: {}",
+        stats.body_failures.load(Ordering::Relaxed)
+    );
+    summary!(
+        "Most attempts for one archive: {}",
+        stats.max_attempts.load(Ordering::Relaxed)
+    );
+    for (url, attempts) in stats.retried.lock().unwrap().iter() {
+        summary!("  {} took {} attempts", url, attempts);
+    }
+    if opt.played_only {
+        summary!("Games not played by the user: {}", parse_stats.not_played);
+    }
+    if opt.max_archive_bytes.is_some() {
+        summary!("Too large: {}", stats.too_large.load(Ordering::Relaxed));
+    }
+    if opt.archive_deadline_secs.is_some() {
+        summary!(
+            "Failed past the deadline: {}",
+            stats.past_deadline.load(Ordering::Relaxed)
+        );
+    }
+    if opt.dedupe_global {
+        summary!("Duplicates across users: {}", parse_stats.duplicates);
+    }
+    if opt.dedupe_by_moves {
+        summary!("Duplicates with the same moves: {}", parse_stats.same_moves);
+    }
+    if opt.verify {
+        summary!("Games that failed verification: {}", parse_stats.unverified);
+    }
+    if opt.split_games {
+        summary!("Game files written: {}", parse_stats.game_files);
+    }
+    if !opt.raw {
+        summary!("Parsed games: {}", parse_stats.parsed());
+        summary!(
+            "Games that failed to parse: {} ({:.2}%)",
+            parse_stats.failed,
+            parse_stats.error_rate()
+        );
+        for (username, games) in parse_stats.users.iter().collect::<BTreeMap<_, _>>() {
+            if let (Some(first), Some(last)) = (games.first, games.last) {
+                summary!("Games of {}: {} to {}", username, first, last);
+            }
+        }
+    }
+    if opt.color_balance_report {
+        print_color_balance(&parse_stats.colors);
+    }
+    if let Some(top) = opt.stats_per_opponent {
+        print_opponents(&parse_stats.opponents, top);
+    }
+    if let Some(report_path) = &opt.report_json {
+        let report = DownloadReport {
+            archives: stats.counts(num_archives),
+            failed: &failed_urls,
+            opponents: &parse_stats.opponents,
+        };
+        serde_json::to_writer_pretty(File::create(report_path)?, &report)?;
+    }
+    for (username, games) in &parse_stats.users {
+        // Stopping early can leave a user without games regardless of the filters.
+        if games.parsed > 0 && games.written == 0 && !stop.is_cancelled() && !opt.parse_only {
+            warn!(
+                "None of the {} games of {} passed the filters, they may be too strict",
+                games.parsed, username
+            );
+        }
+    }
+    if stop.is_cancelled() {
+        summary!("Cancelled: {}", stats.cancelled.load(Ordering::Relaxed));
+    }
+    if let Some(e) = write_error {
+        return Err(e);
+    }
+    if token.is_cancelled() {
+        return Err(DownloadError::Cancelled);
+    }
+    if parse_stats.unverified > 0 {
+        return Err(DownloadError::UnverifiedGames(parse_stats.unverified));
+    }
+    if opt.parse_only && parse_stats.failed > 0 {
+        return Err(DownloadError::InvalidGames(parse_stats.failed));
+    }
+    if let Some(max) = opt.max_parse_error_rate {
+        let rate = parse_stats.error_rate();
+        if rate > max {
+            return Err(DownloadError::TooManyParseErrors { rate, max });
+        }
+    }
+    Ok(())
+}
+
+/// Prints the number of games played with each color by time class to stderr.
+fn print_color_balance(colors: &BTreeMap<String, [usize; 2]>) {
+    eprintln!("{:<8} {:>8} {:>8}", "Time", "White", "Black");
+    let mut total = [0, 0];
+    for (time, [white, black]) in colors {
+        eprintln!("{:<8} {:>8} {:>8}", time, white, black);
+        total[0] += white;
+        total[1] += black;
+    }
+    eprintln!("{:<8} {:>8} {:>8}", "Total", total[0], total[1]);
+}
+
+/// Prints the record of each user against their `top` most frequent opponents to stderr.
+fn print_opponents(opponents: &BTreeMap<String, BTreeMap<String, JSONRecord>>, top: usize) {
+    for (username, opponents) in opponents {
+        eprintln!("Opponents of {}:", username);
+        eprintln!(
+            "{:<24} {:>8} {:>8} {:>8} {:>8}",
+            "Opponent", "Games", "Wins", "Losses", "Draws"
+        );
+        let mut opponents = opponents.iter().collect::<Vec<_>>();
+        opponents
+            .sort_by_key(|(_, record)| std::cmp::Reverse(record.win + record.loss + record.draw));
+        for (opponent, record) in opponents.into_iter().take(top) {
+            eprintln!(
+                "{:<24} {:>8} {:>8} {:>8} {:>8}",
+                opponent,
+                record.win + record.loss + record.draw,
+                record.win,
+                record.loss,
+                record.draw
+            );
+        }
+    }
+}
+
+/// Downloads the daily games a player is currently playing from `url`, since they only show up in
+/// the archives once they are finished. Returns the number of games and their PGN.
+async fn fetch_ongoing_games(client: &Client, url: &str) -> reqwest::Result<(usize, String)> {
+    let ongoing = client
+        .get(url)
+        .send()
+        .await?
+        .json::<JSONOngoingGames>()
+        .await?;
+    let pgns = ongoing
+        .games
+        .into_iter()
+        .filter_map(|game| game.pgn)
+        .collect::<Vec<String>>();
+    let pgn = pgns
+        .iter()
+        .map(|pgn| format!("{}\n\n", pgn.trim_end()))
+        .collect();
+    Ok((pgns.len(), pgn))
+}
+
+/// Sends a downloaded archive to the writer. If the writer stopped, e.g. because it failed to write
+/// a file, the downloads are cancelled since their games couldn't be written anyway.
+fn send_to_writer(send: &Sender<PGNMessage>, stop: &CancellationToken, message: PGNMessage) {
+    if send.send(message).is_err() && !stop.is_cancelled() {
+        error!("The writer stopped, cancelling the downloads");
+        stop.cancel();
+    }
+}
+
+/// Saves the state for --sync-state. It is written under another name first, so that a crash
+/// while saving it keeps the previous state rather than leaving a truncated file.
+fn write_sync_state(path: &Path, state: &BTreeMap<String, String>) -> Result<(), DownloadError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    serde_json::to_writer_pretty(&mut file, state)?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Reads the Last-Modified date of each archive URL saved by a previous run.
+fn read_sync_state(path: &Path) -> Result<BTreeMap<String, String>, DownloadError> {
+    match File::open(path) {
+        Ok(file) => Ok(serde_json::from_reader(file)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Downloads an archive, retrying up to `opt.attempts` times. Returns the number of attempts made
+/// and the message for the writer, if there is anything to write.
+async fn download_archive(
+    opt: &Options,
+    client: &Client,
+    archive: Archive,
+    stats: &DownloadStats,
+    last_modified: &Mutex<BTreeMap<String, String>>,
+    throttle: Option<&Throttle>,
+    mut paused: watch::Receiver<bool>,
+) -> (u32, Option<PGNMessage>) {
+    for attempt in 1..opt.attempts + 1 {
+        // Cancelling the download also stops the wait. The error only means that the signal
+        // handler is gone, so there is nothing to wait for.
+        let _ = paused.wait_for(|paused| !paused).await;
+        let mut request = client.get(&archive.url);
+        if let Some(since) = last_modified.lock().unwrap().get(&archive.url) {
+            request = request.header(IF_MODIFIED_SINCE, since);
+        }
+        match request.send().await {
+            Ok(resp) => {
+                if resp.status() == StatusCode::NOT_MODIFIED {
+                    log!(opt.progress_level(), "{} is unchanged", archive.url);
+                    stats.unchanged.fetch_add(1, Ordering::Relaxed);
+                    return (attempt, None);
+                }
+                if let Err(e) = resp.error_for_status_ref() {
+                    error!("Failed to request {}: {}", archive.url, e);
+                    stats.request_failures.fetch_add(1, Ordering::Relaxed);
+                    let status = resp.status();
+                    // Other client errors, e.g. 404, won't go away by asking again.
+                    if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+                        retry_after(opt, stats, attempt).await;
+                        continue;
+                    }
+                    stats.record_failure(archive.url);
+                    return (attempt, None);
+                }
+                let modified = resp
+                    .headers()
+                    .get(LAST_MODIFIED)
+                    .and_then(|val| val.to_str().ok())
+                    .map(str::to_owned);
+                match read_body(resp, opt.max_archive_bytes, throttle).await {
+                    Ok(None) => {
+                        warn!(
+                            "Skipping {}, it is larger than {} bytes",
+                            archive.url,
+                            opt.max_archive_bytes.unwrap_or_default()
+                        );
+                        stats.too_large.fetch_add(1, Ordering::Relaxed);
+                        return (attempt, None);
+                    }
+                    Ok(Some(bytes)) => {
+                        if bytes.is_empty() {
+                            if attempt == opt.attempts {
+                                error!(
+                                    "Failed to download {} {}/{} times",
+                                    archive.url, attempt, opt.attempts
+                                );
+                                stats.empty.fetch_add(1, Ordering::Relaxed);
+                                let message = PGNMessage {
+                                    month: archive.month(),
+                                    username: archive.username,
+                                    source_url: archive.url,
+                                    last_modified: None,
+                                    bytes: Bytes::from(""),
+                                };
+                                return (attempt, Some(message));
+                            } else {
+                                error!(
+                                    "Failed to download {} {}/{} times. Retrying...",
+                                    archive.url, attempt, opt.attempts
+                                );
+                            }
+                        } else {
+                            log!(
+                                opt.progress_level(),
+                                "Downloaded {} bytes from {}",
+                                bytes.len(),
+                                archive.url
+                            );
+                            stats.downloaded.fetch_add(1, Ordering::Relaxed);
+                            stats.bytes.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                            let message = PGNMessage {
+                                month: archive.month(),
+                                username: archive.username,
+                                source_url: archive.url,
+                                last_modified: modified,
+                                bytes,
+                            };
+                            return (attempt, Some(message));
+                        }
+                    }
+                    Err(e) => {
+                        // The connection can drop after the status arrived, so this is retried too.
+                        error!("Failed to read the body of {}: {}", archive.url, e);
+                        stats.body_failures.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Failed to request {}: {}", archive.url, e);
+                stats.request_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        retry_after(opt, stats, attempt).await;
+    }
+    stats.record_failure(archive.url);
+    (opt.attempts, None)
+}
+
+/// Waits before the next attempt after the `attempt`th one failed, unless it was the last one.
+async fn retry_after(opt: &Options, stats: &DownloadStats, attempt: u32) {
+    if attempt < opt.attempts {
+        stats.retries.fetch_add(1, Ordering::Relaxed);
+        let delay = retry_delay(opt, attempt);
+        log!(
+            opt.progress_level(),
+            "Retrying in {:.1}s...",
+            delay.as_secs_f64()
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Delay after the `attempt`th failure according to --backoff. Unless --no-jitter is given, it is
+/// scaled by a random factor in [0.5, 1.5) so that concurrent downloads don't all retry at the same
+/// moment.
+fn retry_delay(opt: &Options, attempt: u32) -> Duration {
+    let delay = match opt.backoff {
+        Backoff::Exponential => Duration::from_secs(1 << (attempt - 1).min(6)),
+        Backoff::Linear => Duration::from_secs(u64::from(attempt)),
+        Backoff::Constant => Duration::from_secs(1),
+    };
+    if opt.no_jitter {
+        delay
+    } else {
+        delay.mul_f64(0.5 + fastrand::f64())
+    }
+}
+
+/// Reads the response body, or returns `None` if it is larger than `max_bytes`.
+async fn read_body(
+    mut resp: Response,
+    max_bytes: Option<u64>,
+    throttle: Option<&Throttle>,
+) -> reqwest::Result<Option<Bytes>> {
+    if max_bytes.is_none() && throttle.is_none() {
+        return resp.bytes().await.map(Some);
+    }
+    if let (Some(max_bytes), Some(len)) = (max_bytes, resp.content_length()) {
+        if len > max_bytes {
+            return Ok(None);
+        }
+    }
+    // Without a Content-Length, stop reading as soon as the limit is crossed.
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if max_bytes.is_some_and(|max_bytes| (body.len() + chunk.len()) as u64 > max_bytes) {
+            return Ok(None);
+        }
+        if let Some(throttle) = throttle {
+            throttle.consume(chunk.len()).await;
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Some(Bytes::from(body)))
+}
+
+/// State shared by the writer threads.
+struct WriterContext<'a> {
+    game_ids: &'a GameIds,
+    aliases: &'a HashMap<String, String>,
+    budget: &'a GameBudget,
+    sample: Option<&'a Sample>,
+    dumps: Option<&'a UnparsedDumps>,
+    checkpoint: Option<&'a Checkpoint>,
+}
+
+fn parse_pgn_messages(
+    opt: &Options,
+    context: WriterContext,
+    rec: Receiver<PGNMessage>,
+) -> Result<(HashMap<PGNMetadata, GroupFile>, ParseStats), DownloadError> {
+    let WriterContext {
+        game_ids,
+        aliases,
+        budget,
+        sample,
+        dumps,
+        checkpoint,
+    } = context;
+    let mut files = HashMap::<PGNMetadata, GroupFile>::new();
+    let mut stats = ParseStats::default();
+    // Archives processed since the last checkpoint, with their Last-Modified dates.
+    let (mut unsaved, mut unsaved_dates) = (0, BTreeMap::new());
+    let opening_moves = opt.opening_moves.as_deref().map(san_moves);
+    let opening_contains = opt.opening_contains.as_deref().map(str::to_lowercase);
+    for pgn_message in rec {
+        // The games of the archives processed so far are all in `files`.
+        if let Some(checkpoint) = checkpoint {
+            if unsaved >= checkpoint.every {
+                save_checkpoint(opt, checkpoint, &mut files, &mut stats, &mut unsaved_dates)?;
+                unsaved = 0;
+            }
+            unsaved += 1;
+        }
+        if let Some(modified) = &pgn_message.last_modified {
+            stats
+                .synced
+                .insert(pgn_message.source_url.clone(), modified.clone());
+            if checkpoint.is_some() {
+                unsaved_dates.insert(pgn_message.source_url.clone(), modified.clone());
+            }
+        }
+        let mut game_info = PGNMetadata::from_username(&pgn_message.username);
+        if opt.by_archive {
+            game_info.month = pgn_message.month;
+        }
+        game_info.username = group_username(opt, aliases, &pgn_message.username);
+        if opt.raw {
+            group_file(&mut files, game_info)?
+                .file
+                .write_all(&pgn_message.bytes)?;
+        } else {
+            let s = String::from_utf8_lossy(&pgn_message.bytes);
+            if let Cow::Owned(s) = &s {
+                warn!(
+                    "{} is not valid UTF-8, {} replacement characters were introduced",
+                    pgn_message.source_url,
+                    s.matches(char::REPLACEMENT_CHARACTER).count()
+                );
+            }
+            let (games, errors) = if opt.json_games {
+                match parse_json_archive(&s) {
+                    Ok(games) => (games, Vec::new()),
+                    Err(e) => {
+                        stats.failed += 1;
+                        if opt.on_parse_error == ParseErrorPolicy::Abort {
+                            return Err(e);
+                        }
+                        error!("Skipping {}: {}", pgn_message.source_url, e);
+                        if let Some(dumps) = dumps {
+                            dumps.dump(&pgn_message.source_url, &s, &e)?;
+                        }
+                        write_unparsed(opt, &pgn_message)?;
+                        continue;
+                    }
+                }
+            } else if opt.parallel_parse {
+                par_parse_each(&s)
+            } else {
+                match ChessParser::parse(&s) {
+                    Ok(games) => (games.collect(), Vec::new()),
+                    Err(e) => {
+                        warn!(
+                            "Failed to parse {}, parsing its games one by one: {}",
+                            pgn_message.source_url, e
+                        );
+                        parse_each(&s)
+                    }
+                }
+            };
+            stats.failed += errors.len();
+            if !errors.is_empty() {
+                if opt.on_parse_error == ParseErrorPolicy::Abort {
+                    return Err(errors.into_iter().next().unwrap().1);
+                }
+                for (input, e) in &errors {
+                    error!("Skipping a game from {}: {}", pgn_message.source_url, e);
+                    if let Some(dumps) = dumps {
+                        dumps.dump(&pgn_message.source_url, input, e)?;
+                    }
+                }
+                write_unparsed(opt, &pgn_message)?;
+            }
+            let user_games = stats.users.entry(pgn_message.username.clone()).or_default();
+            for mut game in games {
+                user_games.parsed += 1;
+                if let Some(date) = game.local_date(opt.tz) {
+                    user_games.first = user_games.first.into_iter().chain(Some(date)).min();
+                    user_games.last = user_games.last.into_iter().chain(Some(date)).max();
+                }
+                if opt.parse_only {
+                    continue;
+                }
+                if opt.played_only && !game.played_by(&pgn_message.username) {
+                    stats.not_played += 1;
+                    continue;
+                }
+                if opt.time_class_from_header {
+                    if let Some(time) = game.header("TimeClass").and_then(Time::from_time_class) {
+                        game.time = time;
+                    }
+                }
+                if let Some((_, time)) = opt
+                    .remap_time
+                    .iter()
+                    .find(|(times, _)| times.contains(&game.time))
+                {
+                    game.time = *time;
+                }
+                let all = !(opt.bullet | opt.blitz | opt.rapid | opt.daily);
+                let time_allowed = match game.time {
+                    Time::Misc => all,
+                    Time::Bullet => opt.bullet || all,
+                    Time::Blitz => opt.blitz || all,
+                    Time::Rapid => opt.rapid || all,
+                    Time::Daily => opt.daily || all,
+                    Time::None => unreachable!(),
+                };
+                let position_allowed = match game.fen {
+                    Some(_) => !opt.no_custom_position,
+                    None => !opt.from_position_only,
+                };
+                let variant_allowed = !opt.standard_only || game.variant.is_none();
+                let rated_allowed = !opt.rated_only || game.rated == Some(true);
+                let headers_allowed =
+                    opt.filter_header
+                        .iter()
+                        .all(|(attr, val)| match game.header(attr) {
+                            Some(v) if opt.filter_exact => v == val,
+                            Some(v) => v.contains(val.as_str()),
+                            None => false,
+                        });
+                let mate_allowed = !(opt.checkmated || opt.delivered_mate)
+                    || match game.checkmate_by(&pgn_message.username) {
+                        Some(true) => opt.delivered_mate,
+                        Some(false) => opt.checkmated,
+                        None => false,
+                    };
+                let length_allowed = match opt.min_moves {
+                    Some(min_moves) => game.num_moves() >= min_moves,
+                    None => true,
+                };
+                let aborted_allowed = !(opt.skip_aborted && game.is_aborted());
+                let accuracy_allowed = match opt.min_accuracy {
+                    Some(min_accuracy) => game
+                        .accuracy_of(&pgn_message.username)
+                        .is_some_and(|accuracy| accuracy >= min_accuracy),
+                    None => true,
+                };
+                let opening_allowed = match &opening_moves {
+                    Some(moves) => game.starts_with_moves(moves),
+                    None => true,
+                } && match &opening_contains {
+                    Some(name) => game
+                        .opening
+                        .as_ref()
+                        .is_some_and(|opening| opening.to_lowercase().contains(name)),
+                    None => true,
+                };
+                let finished_allowed = !(opt.finished_only && game.result == GameResult::Ongoing);
+                let id_allowed = game_ids.allows(game.id);
+                let date_allowed = opt.after.is_none() && opt.before.is_none()
+                    || match game.local_date(opt.tz) {
+                        Some(date) => {
+                            opt.after.iter().all(|&after| date >= after)
+                                && opt.before.iter().all(|&before| date <= before)
+                        }
+                        None => false,
+                    };
+                let allowed = time_allowed
+                    && position_allowed
+                    && variant_allowed
+                    && rated_allowed
+                    && headers_allowed
+                    && mate_allowed
+                    && length_allowed
+                    && aborted_allowed
+                    && finished_allowed
+                    && accuracy_allowed
+                    && opening_allowed
+                    && date_allowed
+                    && id_allowed;
+                // Checked last so that a game filtered out for one user is still kept for another.
+                let duplicate = allowed && opt.dedupe_global && !game_ids.first_occurrence(game.id);
+                if duplicate {
+                    stats.duplicates += 1;
+                }
+                let same_moves = allowed
+                    && !duplicate
+                    && opt.dedupe_by_moves
+                    && !game_ids.first_moves(game.moves_fingerprint());
+                if same_moves {
+                    stats.same_moves += 1;
+                }
+                if allowed && !duplicate && !same_moves && budget.take() {
+                    if !opt.keep_tags.is_empty() {
+                        game.retain_headers(|attr| opt.keep_tags.iter().any(|tag| tag == attr));
+                    } else if !opt.drop_tags.is_empty() {
+                        game.retain_headers(|attr| opt.drop_tags.iter().all(|tag| tag != attr));
+                    }
+                    if opt.export_clocks {
+                        game.clocks = clock_times(&game.moves);
+                    }
+                    if opt.strip_comments {
+                        game.strip_comments();
+                    }
+                    if opt.pretty {
+                        game.pgn = game.pretty_pgn();
+                    }
+                    if opt.tag_player {
+                        game.add_header("DownloadedFor", &pgn_message.username);
+                    }
+                    if opt.stamp_provenance {
+                        game.add_header("DownloadedAt", &utc_now());
+                        game.add_header("SourceArchive", &pgn_message.source_url);
+                    }
+                    if opt.headers_only {
+                        game.pgn = game.header_section();
+                    }
+                    if opt.verify && !survives_roundtrip(&game) {
+                        error!(
+                            "A game from {} is not valid PGN after rewriting it:\n{}",
+                            pgn_message.source_url, game.pgn
+                        );
+                        stats.unverified += 1;
+                    }
+                    let mut game_info = if opt.by_archive {
+                        let mut game_info = PGNMetadata::from_username(&pgn_message.username);
+                        game_info.month = pgn_message.month;
+                        game_info
+                    } else {
+                        PGNMetadata::from_game(
+                            &pgn_message.username,
+                            &game,
+                            !opt.timesort,
+                            !opt.variantsort,
+                        )
+                    };
+                    game_info.merge_time(&opt.merge_time);
+                    if opt.by_year {
+                        let year = game
+                            .local_date(opt.tz)
+                            .map(|date| date.year)
+                            .or(pgn_message.month.map(|(year, _)| year));
+                        game_info.year = Some(match year {
+                            Some(year) => format!("{:04}", year),
+                            None => NO_YEAR.to_owned(),
+                        });
+                    }
+                    if opt.group_by_opening_name {
+                        game_info.opening = Some(
+                            game.opening
+                                .clone()
+                                .unwrap_or_else(|| UNKNOWN_OPENING.to_owned()),
+                        );
+                    }
+                    game_info.username = group_username(opt, aliases, &pgn_message.username);
+                    user_games.written += 1;
+                    if opt.normalize_usernames_from_pgn {
+                        if let Some(name) = game.display_name_of(&pgn_message.username) {
+                            stats
+                                .display_names
+                                .entry(pgn_message.username.clone())
+                                .or_insert_with(|| name.to_owned());
+                        }
+                    }
+                    if game.played_by(&pgn_message.username) {
+                        let colors = stats.colors.entry(game.time.to_string()).or_default();
+                        colors[usize::from(game.white != pgn_message.username)] += 1;
+                        if opt.stats_per_opponent.is_some() {
+                            let white = game.white == pgn_message.username;
+                            let opponent = if white { &game.black } else { &game.white };
+                            let record = stats
+                                .opponents
+                                .entry(pgn_message.username.clone())
+                                .or_default()
+                                .entry(opponent.clone())
+                                .or_default();
+                            match (game.result, white) {
+                                (GameResult::WhiteWins, true) | (GameResult::BlackWins, false) => {
+                                    record.win += 1
+                                }
+                                (GameResult::WhiteWins, false) | (GameResult::BlackWins, true) => {
+                                    record.loss += 1
+                                }
+                                (GameResult::Draw, _) => record.draw += 1,
+                                _ => (),
+                            }
+                        }
+                    }
+                    if opt.stdout {
+                        let mut stdout = std::io::stdout().lock();
+                        Format::Jsonl.write_game(&mut stdout, &game)?;
+                        stdout.flush()?;
+                    } else if let Some(sample) = sample {
+                        sample.offer(&pgn_message.username, game_info, game);
+                    } else {
+                        write_grouped(opt, &mut files, game_info, &game)?;
+                        if opt.split_games {
+                            stats.game_files += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok((files, stats))
+}
+
+/// Writes the raw archive of `pgn_message` to `<user>_<month>_unparsed.pgn` with
+/// `--on-parse-error raw-fallback`, so that the games which failed to parse aren't lost.
+fn write_unparsed(opt: &Options, pgn_message: &PGNMessage) -> Result<(), DownloadError> {
+    if opt.on_parse_error != ParseErrorPolicy::RawFallback || !opt.writes_files() {
+        return Ok(());
+    }
+    let mut game_info = PGNMetadata::from_username(&pgn_message.username);
+    game_info.month = pgn_message.month;
+    let name = game_info.file_name(true, "pgn");
+    let path = opt
+        .destination_dir()
+        .join(format!("{}_unparsed.pgn", name.trim_end_matches(".pgn")));
+    std::fs::write(&path, &pgn_message.bytes)?;
+    warn!(
+        "Wrote the raw games of {} to {}",
+        pgn_message.source_url,
+        path.display()
+    );
+    Ok(())
+}
+
+/// Merges the games written so far into the output files and saves --sync-state with the archives
+/// they came from.
+fn save_checkpoint(
+    opt: &Options,
+    checkpoint: &Checkpoint,
+    files: &mut HashMap<PGNMetadata, GroupFile>,
+    stats: &mut ParseStats,
+    dates: &mut BTreeMap<String, String>,
+) -> Result<(), DownloadError> {
+    let mut state = checkpoint.state.lock().unwrap();
+    let include_username = !(opt.flatten_single_user && opt.usernames.len() == 1);
+    for (game_info, group) in files.iter_mut().filter(|(_, group)| group.games > 0) {
+        let output_path = opt
+            .destination_dir()
+            .join(game_info.file_name(include_username, opt.format.extension()));
+        group.file.seek(SeekFrom::Start(0))?;
+        let (games, bytes) = merge_games(&mut group.file, &output_path, opt.progress_level())?;
+        let merged = stats.checkpointed.entry(game_info.clone()).or_default();
+        merged.0 += games;
+        merged.1 += bytes;
+        group.file.set_len(0)?;
+        group.file.seek(SeekFrom::Start(0))?;
+        group.games = 0;
+    }
+    state.append(dates);
+    write_sync_state(&checkpoint.path, &state)?;
+    log!(
+        opt.progress_level(),
+        "Saved a checkpoint to {}",
+        checkpoint.path.display()
+    );
+    Ok(())
+}
+
+/// Writes `game` to its own file in the output directory. If the name is taken, e.g. by a rematch on
+/// the same day, the game ID is appended, and then a counter.
+fn write_game_file(opt: &Options, game: &Game) -> Result<(), DownloadError> {
+    let stem = game.file_stem(opt.tz);
+    let with_id = game.id.map(|id| format!("{}_{}", stem, id));
+    let last = with_id.clone().unwrap_or_else(|| stem.clone());
+    let stems = std::iter::once(stem)
+        .chain(with_id)
+        .chain((2..).map(|n| format!("{}_{}", last, n)));
+    for stem in stems {
+        let path = opt
+            .destination_dir()
+            .join(format!("{}.{}", stem, opt.format.extension()));
+        let file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let mut game_bytes = Vec::new();
+        opt.format.write_game(&mut game_bytes, game)?;
+        if opt.format.write_database(&game_bytes[..], &path)?.is_some() {
+            return Ok(());
+        }
+        opt.format
+            .write_file(&game_bytes[..], &mut opt.encoding.writer(file))?;
+        return Ok(());
+    }
+    unreachable!("there are infinitely many file names")
+}
+
+/// Name the files of `username` are grouped under. With --user-sections the users are only put
+/// together once all games are written.
+fn group_username(opt: &Options, aliases: &HashMap<String, String>, username: &str) -> String {
+    if opt.group_users && !opt.user_sections {
+        String::from(ALL_USERS)
+    } else {
+        aliases
+            .get(username)
+            .map_or(username, String::as_str)
+            .to_owned()
+    }
+}
+
+/// Whether the PGN of `game` parses back into the same game, after options that rewrite it.
+fn survives_roundtrip(game: &Game) -> bool {
+    let parsed = match ChessParser::parse(&game.pgn) {
+        Ok(parsed) => parsed.collect::<Vec<_>>(),
+        Err(_) => return false,
+    };
+    match parsed.as_slice() {
+        [parsed] => {
+            // Tags removed with --keep-tags or --drop-tags are missing from both.
+            let kept = |attr| game.header(attr).is_some();
+            (!kept("White") || parsed.white == game.white)
+                && (!kept("Black") || parsed.black == game.black)
+                && (!kept("Result") || parsed.result == game.result)
+                && parsed.moves_fingerprint() == game.moves_fingerprint()
+        }
+        _ => false,
+    }
+}
+
+/// Writes `game` to the temporary file of its group, or to its own file with --split-games.
+fn write_grouped(
+    opt: &Options,
+    files: &mut HashMap<PGNMetadata, GroupFile>,
+    game_info: PGNMetadata,
+    game: &Game,
+) -> Result<(), DownloadError> {
+    if opt.split_games {
+        write_game_file(opt, game)
+    } else {
+        let group = group_file(files, game_info)?;
+        opt.format.write_game(&mut group.file, game)?;
+        group.games += 1;
+        Ok(())
+    }
+}
+
+/// Temporary file of the group, created on first use.
+fn group_file(
+    files: &mut HashMap<PGNMetadata, GroupFile>,
+    game_info: PGNMetadata,
+) -> std::io::Result<&mut GroupFile> {
+    Ok(match files.entry(game_info) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(GroupFile {
+            file: tempfile::tempfile()?,
+            games: 0,
+        }),
+    })
+}
+
+fn process_pgn_messages(
+    opt: &Options,
+    game_ids: Arc<GameIds>,
+    aliases: Arc<HashMap<String, String>>,
+    budget: Arc<GameBudget>,
+    checkpoint: Option<Arc<Checkpoint>>,
+    rec: Receiver<PGNMessage>,
+) -> Result<ParseStats, DownloadError> {
+    let sample = opt.sample.map(|size| {
+        Arc::new(Sample {
+            size,
+            per_user: opt.sample_per_user,
+            reservoirs: Mutex::new(HashMap::new()),
+        })
+    });
+    let dumps = match &opt.dump_unparsed {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)?;
+            Some(Arc::new(UnparsedDumps {
+                dir: dir.clone(),
+                max: opt.max_unparsed_dumps,
+                written: AtomicUsize::new(0),
+            }))
+        }
+        None => None,
+    };
+    let workers = (0..opt.writers)
+        .map(|_| {
+            let opt = opt.clone();
+            let sample = sample.clone();
+            let dumps = dumps.clone();
+            let checkpoint = checkpoint.clone();
+            let game_ids = game_ids.clone();
+            let aliases = aliases.clone();
+            let budget = budget.clone();
+            let rec = rec.clone();
+            std::thread::spawn(move || {
+                let context = WriterContext {
+                    game_ids: &game_ids,
+                    aliases: &aliases,
+                    budget: &budget,
+                    sample: sample.as_deref(),
+                    dumps: dumps.as_deref(),
+                    checkpoint: checkpoint.as_deref(),
+                };
+                let parsed = parse_pgn_messages(&opt, context, rec);
+                if parsed.is_err() {
+                    // The other workers keep the channel open, so stop the downloads here.
+                    budget.stop.cancel();
+                }
+                parsed
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(rec);
+
+    // Each worker has its own temporary file per group, so merge them.
+    let mut files = HashMap::<PGNMetadata, GroupFile>::new();
+    let mut stats = ParseStats::default();
+    for worker in workers {
+        let (worker_files, worker_stats) =
+            worker.join().map_err(|_| DownloadError::WriterPanicked)??;
+        stats.merge(worker_stats);
+        for (game_info, mut group) in worker_files {
+            match files.entry(game_info) {
+                Entry::Occupied(mut entry) => {
+                    group.file.seek(SeekFrom::Start(0))?;
+                    std::io::copy(&mut group.file, &mut entry.get_mut().file)?;
+                    entry.get_mut().games += group.games;
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(group);
+                }
+            }
+        }
+    }
+    if let Some(sample) = &sample {
+        for (game_info, game) in sample.take_games() {
+            write_grouped(opt, &mut files, game_info, &game)?;
+            if opt.split_games {
+                stats.game_files += 1;
+            }
+        }
+    }
+    if opt.user_sections {
+        let mut users = files.into_iter().collect::<Vec<_>>();
+        users.sort_by(|(a, _), (b, _)| a.username.cmp(&b.username));
+        files = HashMap::new();
+        for (mut game_info, mut group) in users {
+            let username = std::mem::replace(&mut game_info.username, String::from(ALL_USERS));
+            let name = stats.display_names.get(&username).unwrap_or(&username);
+            let section = group_file(&mut files, game_info)?;
+            write!(section.file, "{{ === user: {} === }}\n\n", name)?;
+            group.file.seek(SeekFrom::Start(0))?;
+            std::io::copy(&mut group.file, &mut section.file)?;
+            section.games += group.games;
+        }
+    }
+
+    if !stats.display_names.is_empty() {
+        // Files are grouped by the lowercased name, which is only replaced in the file names.
+        files = files
+            .into_iter()
+            .map(|(mut game_info, group)| {
+                if let Some(name) = stats.display_names.get(&game_info.username) {
+                    game_info.username = name.clone();
+                }
+                (game_info, group)
+            })
+            .collect();
+    }
+    let include_username = !(opt.flatten_single_user && opt.usernames.len() == 1);
+    let mut manifest = Vec::new();
+    // Sorted so that colliding file names are disambiguated the same way on every run.
+    let mut groups = files.iter_mut().collect::<Vec<_>>();
+    groups.sort_by_cached_key(|(game_info, _)| {
+        (
+            game_info.file_name(include_username, opt.format.extension()),
+            game_info.to_string(),
+        )
+    });
+    let mut used_names = HashSet::new();
+    let mut toc = match &opt.toc {
+        Some(toc_path) => {
+            let mut toc = BufWriter::new(File::create(toc_path)?);
+            writeln!(toc, "file\toffset\twhite\tblack\tdate\tresult")?;
+            Some(toc)
+        }
+        None => None,
+    };
+    for (game_info, group) in groups {
+        let tmp_file = &mut group.file;
+        tmp_file.seek(SeekFrom::Start(0))?;
+
+        let output_str = unique_file_name(
+            &mut used_names,
+            game_info.file_name(include_username, opt.format.extension()),
+        );
+        let output_path = opt.destination_dir().join(output_str);
+        let (games, num_bytes) = if opt.merge_into.is_some() {
+            merge_games(tmp_file, &output_path, opt.progress_level())?
+        } else if let Some(num_bytes) = opt.format.write_database(&mut *tmp_file, &output_path)? {
+            (group.games, num_bytes)
+        } else {
+            let dest_file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&output_path)?;
+            let mut dest_file = opt.encoding.writer(dest_file);
+            log!(
+                opt.progress_level(),
+                "Copying temporary file to {}...",
+                output_path.display()
+            );
+            if let Some(toc) = &mut toc {
+                write_with_toc(opt, tmp_file, &mut dest_file, &output_path, toc)?;
+            } else if opt.newest_first_output {
+                write_newest_first(tmp_file, &mut dest_file)?;
+            } else {
+                opt.format.write_file(tmp_file, &mut dest_file)?;
+            }
+            dest_file.flush()?;
+            // Taken from the file since --encoding can change the size of the games.
+            let num_bytes = std::fs::metadata(&output_path)?.len();
+            log!(
+                opt.progress_level(),
+                "Number of bytes copied: {}",
+                num_bytes
+            );
+            (group.games, num_bytes)
+        };
+        let (games, num_bytes) = match stats.checkpointed.get(game_info) {
+            Some((merged, merged_bytes)) => (games + merged, num_bytes + merged_bytes),
+            None => (games, num_bytes),
+        };
+        manifest.push(ManifestEntry {
+            file: output_path.display().to_string(),
+            username: game_info.username.clone(),
+            color: game_info.color.to_string(),
+            variant: game_info.variant.clone(),
+            time: game_info
+                .time_name()
+                .unwrap_or_else(|| Time::None.to_string()),
+            games: if opt.raw { None } else { Some(games) },
+            bytes: num_bytes,
+        });
+    }
+    if let Some(toc) = &mut toc {
+        toc.flush()?;
+    }
+    if let Some(manifest_path) = &opt.manifest {
+        serde_json::to_writer_pretty(File::create(manifest_path)?, &manifest)?;
+    }
+    Ok(stats)
+}
+
+/// Writes the games of `tmp_file` like `write_newest_first` or `Format::write_file`, adding a
+/// line to `toc` for each game with its byte offset in `dest_path`.
+fn write_with_toc<W: Write, T: Write>(
+    opt: &Options,
+    tmp_file: &mut File,
+    dest_file: &mut W,
+    dest_path: &Path,
+    toc: &mut T,
+) -> Result<(), DownloadError> {
+    let mut pgn = String::new();
+    tmp_file.read_to_string(&mut pgn)?;
+    let mut games = ChessParser::parse(&pgn)?.collect::<Vec<_>>();
+    if opt.newest_first_output {
+        games.sort_by_key(|game| std::cmp::Reverse(game.timestamp));
+    }
+    let mut offset = 0;
+    for game in &games {
+        writeln!(
+            toc,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            dest_path.display(),
+            offset,
+            game.white_display,
+            game.black_display,
+            game.header("Date").unwrap_or("?"),
+            game.header("Result").unwrap_or("*")
+        )?;
+        dest_file.write_all(game.pgn.as_bytes())?;
+        offset += opt.encoding.encoded_len(&game.pgn);
+    }
+    Ok(())
+}
+
+/// Writes the games of `tmp_file` sorted from newest to oldest.
+/// Since archives are downloaded concurrently, this needs to read all games of the file into memory.
+fn write_newest_first<W: Write>(
+    tmp_file: &mut File,
+    dest_file: &mut W,
+) -> Result<(), DownloadError> {
+    let mut pgn = String::new();
+    tmp_file.read_to_string(&mut pgn)?;
+    let mut games = ChessParser::parse(&pgn)?.collect::<Vec<_>>();
+    games.sort_by_key(|game| std::cmp::Reverse(game.timestamp));
+    for game in &games {
+        dest_file.write_all(game.pgn.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Appends `_2`, `_3`, ... to `name` until it differs from the names in `used`, ignoring case
+/// for case-insensitive file systems. This happens when a username looks like another group's
+/// file name, e.g. a user named "AllUsers".
+fn unique_file_name(used: &mut HashSet<String>, name: String) -> String {
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((&name, ""));
+    let mut unique = name.clone();
+    let mut n = 2;
+    while !used.insert(unique.to_lowercase()) {
+        unique = format!("{}_{}.{}", stem, n, extension);
+        n += 1;
+    }
+    if n > 2 {
+        warn!("{} is used by another group, writing to {}", name, unique);
+    }
+    unique
+}
+
+/// Appends the games of `tmp_file` to `dest_path`, skipping the ones it already contains.
+/// Returns the number of games and bytes appended.
+fn merge_games(
+    tmp_file: &mut File,
+    dest_path: &Path,
+    log_level: Level,
+) -> Result<(usize, u64), DownloadError> {
+    let existing = match std::fs::read_to_string(dest_path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut fingerprints = ChessParser::parse(&existing)?
+        .map(|game| game.fingerprint())
+        .collect::<HashSet<u64>>();
+
+    let mut new_games = String::new();
+    tmp_file.read_to_string(&mut new_games)?;
+
+    let mut dest_file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(dest_path)?;
+    let mut separate = !existing.is_empty() && !existing.ends_with("\n\n");
+    let (mut merged, mut skipped, mut num_bytes) = (0, 0, 0);
+    for game in ChessParser::parse(&new_games)? {
+        if fingerprints.insert(game.fingerprint()) {
+            if std::mem::take(&mut separate) {
+                dest_file.write_all(b"\n")?;
+            }
+            dest_file.write_all(game.pgn.as_bytes())?;
+            merged += 1;
+            num_bytes += game.pgn.len() as u64;
+        } else {
+            skipped += 1;
+        }
+    }
+    log!(
+        log_level,
+        "Merged {} new games into {}, skipped {} duplicates",
+        merged,
+        dest_path.display(),
+        skipped
+    );
+    Ok((merged, num_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn months(patterns: &[&str]) -> Vec<(i64, u32)> {
+        let patterns = patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        expand_months(&patterns, (2023, 5))
+    }
+
+    #[test]
+    fn expand_single_months() {
+        assert_eq!(months(&["2023/05", " 2022/1 "]), [(2022, 1), (2023, 5)]);
+        assert_eq!(months(&["2023/13", "2023", "may"]), []);
+    }
+
+    #[test]
+    fn expand_ranges() {
+        assert_eq!(
+            months(&["2022/11-2023/02"]),
+            [(2022, 11), (2022, 12), (2023, 1), (2023, 2)]
+        );
+        // Overlapping patterns are merged and the future is cut off.
+        assert_eq!(
+            months(&["2023/03-2023/04", "2023/04-2023/08"]),
+            [(2023, 3), (2023, 4), (2023, 5)]
+        );
+        assert_eq!(months(&["2023/04-2023/02"]), []);
+    }
+
+    #[test]
+    fn expand_years() {
+        assert_eq!(months(&["2022/*"]).len(), 12);
+        assert_eq!(
+            months(&["2023/*"]),
+            [(2023, 1), (2023, 2), (2023, 3), (2023, 4), (2023, 5)]
+        );
+    }
+
+    #[test]
+    fn unique_file_names() {
+        let mut used = HashSet::new();
+        // A user named like the file of the grouped users.
+        let merged = PGNMetadata::from_username(ALL_USERS).file_name(true, "pgn");
+        let user = PGNMetadata::from_username("allusers").file_name(true, "pgn");
+        assert_eq!(unique_file_name(&mut used, merged.clone()), "AllUsers.pgn");
+        assert_eq!(unique_file_name(&mut used, user), "allusers_2.pgn");
+        assert_eq!(unique_file_name(&mut used, merged), "AllUsers_3.pgn");
+        assert_eq!(
+            unique_file_name(&mut used, "alice_White.pgn".to_owned()),
+            "alice_White.pgn"
+        );
+    }
+}
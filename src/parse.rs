@@ -1,7 +1,10 @@
 use pest::iterators::Pairs;
 use pest::Parser;
+use rayon::prelude::*;
+use serde::Deserialize;
 
-use crate::types::{Game, Time};
+use crate::error::DownloadError;
+use crate::types::{game_id_from_link, utc_timestamp, Game, GameResult, Time};
 
 #[derive(pest_derive::Parser)]
 #[grammar = "pgn.pest"]
@@ -12,42 +15,391 @@ pub struct ChessParser<'a> {
 }
 
 impl<'a> ChessParser<'a> {
-    pub fn parse(input: &str) -> ChessParser {
+    pub fn parse(input: &str) -> Result<ChessParser<'_>, DownloadError> {
         let pgn = PGNParser::parse(Rule::games, input)
-            .expect("failed parse")
+            .map_err(|e| DownloadError::Parse {
+                source: Box::new(e),
+            })?
             .next()
             .unwrap();
-        ChessParser {
+        Ok(ChessParser {
             pgn: pgn.into_inner(),
+        })
+    }
+}
+/// Games that failed to parse, with their part of the input.
+pub type GameErrors<'a> = Vec<(&'a str, DownloadError)>;
+
+/// Parses each game of `input` on its own, so that a malformed game doesn't prevent parsing the
+/// others. Returns the games that were parsed and the errors of the ones that weren't.
+pub fn parse_each(input: &str) -> (Vec<Game>, GameErrors<'_>) {
+    partition(split_games(input).into_iter().map(parse_game))
+}
+
+/// Same as `parse_each`, but parses the games on all cores.
+pub fn par_parse_each(input: &str) -> (Vec<Game>, GameErrors<'_>) {
+    let parsed = split_games(input)
+        .par_iter()
+        .map(|game| parse_game(game))
+        .collect::<Vec<_>>();
+    partition(parsed.into_iter())
+}
+
+fn parse_game(input: &str) -> Result<Vec<Game>, (&str, DownloadError)> {
+    match ChessParser::parse(input) {
+        Ok(games) => Ok(games.collect()),
+        Err(e) => Err((input, e)),
+    }
+}
+
+fn partition<'a, I>(parsed: I) -> (Vec<Game>, GameErrors<'a>)
+where
+    I: Iterator<Item = Result<Vec<Game>, (&'a str, DownloadError)>>,
+{
+    let mut games = Vec::new();
+    let mut errors = Vec::new();
+    for result in parsed {
+        match result {
+            Ok(parsed) => games.extend(parsed),
+            Err(e) => errors.push(e),
+        }
+    }
+    (games, errors)
+}
+
+/// Splits `input` before each header section that follows moves.
+fn split_games(input: &str) -> Vec<&str> {
+    let mut games = Vec::new();
+    let (mut start, mut offset, mut in_moves) = (0, 0, false);
+    for line in input.split_inclusive('\n') {
+        if line.starts_with('[') {
+            if in_moves {
+                games.push(&input[start..offset]);
+                start = offset;
+                in_moves = false;
+            }
+        } else if !line.trim().is_empty() {
+            in_moves = true;
         }
+        offset += line.len();
+    }
+    if start < input.len() {
+        games.push(&input[start..]);
     }
+    games
 }
+
 impl<'a> std::iter::Iterator for ChessParser<'a> {
     type Item = Game;
     fn next(&mut self) -> Option<Self::Item> {
         let game = self.pgn.next()?;
         match game.as_rule() {
             Rule::game => {
-                let mut g = Game {
-                    pgn: game.as_str().to_owned(),
-                    ..Default::default()
-                };
-                for header_line in game.into_inner() {
-                    let mut header_line_in = header_line.into_inner();
-                    // header_line
-                    let attr = header_line_in.next().unwrap().as_str();
-                    let val = header_line_in.next().unwrap().as_str();
-                    match attr {
-                        "White" => g.white = val.to_lowercase(),
-                        "Black" => g.black = val.to_lowercase(),
-                        "TimeControl" => g.time = Time::parse(val),
-                        _ => (),
+                let pgn = game.as_str().to_owned();
+                let mut headers = Vec::new();
+                let mut moves = String::new();
+                for pair in game.into_inner() {
+                    match pair.as_rule() {
+                        Rule::header_line => {
+                            let mut header_line_in = pair.into_inner();
+                            // header_line
+                            let attr = header_line_in.next().unwrap().as_str();
+                            let val = header_line_in.next().unwrap().as_str();
+                            headers.push((attr.to_owned(), val.to_owned()));
+                        }
+                        Rule::moves => moves = pair.as_str().to_owned(),
+                        _ => unreachable!(),
                     }
                 }
-                Some(g)
+                Some(game_from_parts(pgn, headers, moves))
             }
             Rule::EOI => None,
             _ => unreachable!(),
         }
     }
 }
+
+/// Builds a game from its PGN, split into header tags and moves.
+fn game_from_parts(pgn: String, headers: Vec<(String, String)>, moves: String) -> Game {
+    let mut g = Game {
+        pgn,
+        moves,
+        ..Default::default()
+    };
+    let mut setup = false;
+    let (mut event, mut utc_date, mut utc_time, mut eco_url) = ("", "", "", "");
+    for (attr, val) in &headers {
+        match attr.as_str() {
+            "White" => g.white_display = val.to_owned(),
+            "Black" => g.black_display = val.to_owned(),
+            "Result" => g.result = GameResult::parse(val),
+            "TimeControl" => g.time = Time::parse(val),
+            "SetUp" => setup = val == "1",
+            "FEN" => g.fen = Some(val.to_owned()),
+            "Variant" => g.variant = Some(val.to_owned()),
+            "Opening" => g.opening = Some(val.to_owned()),
+            "ECOUrl" => eco_url = val,
+            "Event" => event = val,
+            "Link" => g.id = game_id_from_link(val),
+            "UTCDate" => utc_date = val,
+            "UTCTime" => utc_time = val,
+            _ => (),
+        }
+    }
+    g.white = g.white_display.to_lowercase();
+    g.black = g.black_display.to_lowercase();
+    g.timestamp = utc_timestamp(utc_date, utc_time);
+    if g.variant.is_none() {
+        // chess.com names the variant in the event, e.g. "Live Chess - Chess960".
+        g.variant = event
+            .split_once(" - ")
+            .map(|(_, variant)| variant.to_owned());
+    }
+    if g.opening.is_none() {
+        // e.g. "https://www.chess.com/openings/Sicilian-Defense-Najdorf-Variation".
+        g.opening = eco_url
+            .rsplit('/')
+            .next()
+            .filter(|name| !name.is_empty())
+            .map(|name| name.replace('-', " "));
+    }
+    if !setup {
+        g.fen = None;
+    }
+    g.headers = headers;
+    g
+}
+
+/// Games of a monthly archive from the JSON endpoint.
+#[derive(Deserialize, Debug)]
+struct JSONArchive {
+    games: Vec<JSONGame>,
+}
+
+#[derive(Deserialize, Debug)]
+struct JSONGame {
+    url: String,
+    pgn: Option<String>,
+    time_class: String,
+    rules: String,
+    rated: Option<bool>,
+    white: JSONPlayer,
+    black: JSONPlayer,
+    /// Only present for analyzed games.
+    accuracies: Option<JSONAccuracies>,
+}
+
+#[derive(Deserialize, Debug)]
+struct JSONAccuracies {
+    white: Option<f32>,
+    black: Option<f32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct JSONPlayer {
+    username: String,
+    /// e.g. "win", "checkmated" or "agreed".
+    result: String,
+}
+
+const DRAW_RESULTS: [&str; 7] = [
+    "agreed",
+    "repetition",
+    "stalemate",
+    "insufficient",
+    "50move",
+    "timevsinsufficient",
+    "draw",
+];
+
+/// Reads the games of an archive from chess.com's JSON endpoint without parsing their PGN. The
+/// players, result, time class and variant come from the JSON fields, and the header tags are only
+/// read for the rest.
+pub fn parse_json_archive(input: &str) -> Result<Vec<Game>, DownloadError> {
+    let archive: JSONArchive = serde_json::from_str(input)?;
+    Ok(archive
+        .games
+        .into_iter()
+        .filter_map(|game| {
+            let pgn = format!("{}\n\n", game.pgn?.trim_end());
+            let (headers, moves) = split_pgn(&pgn);
+            let mut g = game_from_parts(pgn, headers, moves);
+            g.white = game.white.username.to_lowercase();
+            g.black = game.black.username.to_lowercase();
+            g.white_display = game.white.username;
+            g.black_display = game.black.username;
+            g.result = match (game.white.result.as_str(), game.black.result.as_str()) {
+                ("win", _) => GameResult::WhiteWins,
+                (_, "win") => GameResult::BlackWins,
+                (result, _) if DRAW_RESULTS.contains(&result) => GameResult::Draw,
+                _ => g.result,
+            };
+            g.time = Time::from_time_class(&game.time_class).unwrap_or(g.time);
+            g.variant = match game.rules.as_str() {
+                "chess" => None,
+                rules => Some(rules.to_owned()),
+            };
+            g.id = game_id_from_link(&game.url).or(g.id);
+            g.rated = game.rated;
+            if let Some(accuracies) = game.accuracies {
+                g.white_accuracy = accuracies.white;
+                g.black_accuracy = accuracies.black;
+            }
+            Some(g)
+        })
+        .collect())
+}
+
+/// Splits a PGN into its header tags and moves without the grammar.
+fn split_pgn(pgn: &str) -> (Vec<(String, String)>, String) {
+    let mut headers = Vec::new();
+    let mut moves = Vec::new();
+    for line in pgn.lines() {
+        let header = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix("\"]"))
+            .and_then(|line| line.split_once(" \""));
+        match header {
+            Some((attr, val)) => headers.push((attr.to_owned(), val.to_owned())),
+            None if !line.trim().is_empty() => moves.push(line),
+            None => (),
+        }
+    }
+    (headers, moves.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAMES: &str = r#"[Event "Live Chess"]
+[White "Alice"]
+[Black "Bob"]
+[Result "1-0"]
+[TimeControl "180+2"]
+[Link "https://www.chess.com/game/live/42"]
+
+1. e4 {[%clk 0:03:01]} e5 {[%clk 0:03:00.5]} 2. Qh5 Nc6 3. Bc4 Nf6 4. Qxf7# 1-0
+
+[Event "Live Chess - Chess960"]
+[White "Bob"]
+[Black "Alice"]
+[Result "1/2-1/2"]
+[TimeControl "60"]
+[ECOUrl "https://www.chess.com/openings/Sicilian-Defense-Najdorf-Variation"]
+
+1. e4 c5 1/2-1/2
+
+"#;
+
+    #[test]
+    fn split_games_at_headers() {
+        let games = split_games(GAMES);
+        assert_eq!(games.len(), 2);
+        assert!(games[1].starts_with("[Event \"Live Chess - Chess960\"]"));
+        assert_eq!(games.concat(), GAMES);
+    }
+
+    #[test]
+    fn parse_each_keeps_valid_games() {
+        // The second game has no moves.
+        let input = GAMES.replace("1. e4 c5 1/2-1/2\n", "");
+        let (games, errors) = parse_each(&input);
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].white, "alice");
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn par_parse_each_keeps_valid_games() {
+        let input = GAMES.replace("1. e4 c5 1/2-1/2\n", "");
+        let (games, errors) = par_parse_each(&input);
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].white, "alice");
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn json_archive() {
+        let pgn = GAMES.split("\n\n[").next().unwrap();
+        let input = serde_json::json!({
+            "games": [
+                {
+                    "url": "https://www.chess.com/game/live/7",
+                    "pgn": pgn,
+                    "time_class": "rapid",
+                    "rules": "chess",
+                    "rated": true,
+                    "white": { "username": "Alice", "result": "timeout" },
+                    "black": { "username": "Bob", "result": "win" }
+                },
+                {
+                    "url": "https://www.chess.com/game/live/8",
+                    "time_class": "blitz",
+                    "rules": "chess",
+                    "white": { "username": "Alice", "result": "win" },
+                    "black": { "username": "Bob", "result": "resigned" }
+                }
+            ]
+        });
+        let games = parse_json_archive(&input.to_string()).unwrap();
+        // Games without a PGN are skipped.
+        assert_eq!(games.len(), 1);
+        let game = &games[0];
+        // The JSON fields take precedence over the header tags.
+        assert_eq!(game.result, GameResult::BlackWins);
+        assert_eq!(game.time, Time::Rapid);
+        assert_eq!(game.id, Some(7));
+        assert_eq!(game.rated, Some(true));
+        assert_eq!(game.header("TimeControl"), Some("180+2"));
+        assert!(game.moves.starts_with("1. e4"));
+    }
+
+    #[test]
+    fn json_accuracies() {
+        let input = serde_json::json!({
+            "games": [{
+                "url": "https://www.chess.com/game/live/7",
+                "pgn": GAMES.split("\n\n[").next().unwrap(),
+                "time_class": "rapid",
+                "rules": "chess",
+                "white": { "username": "Alice", "result": "win" },
+                "black": { "username": "Bob", "result": "resigned" },
+                "accuracies": { "white": 81.5 }
+            }]
+        });
+        let games = parse_json_archive(&input.to_string()).unwrap();
+        assert_eq!(games[0].white_accuracy, Some(81.5));
+        assert_eq!(games[0].black_accuracy, None);
+    }
+
+    #[test]
+    fn parse_errors_keep_their_input() {
+        let input = GAMES.replace("1. e4 c5 1/2-1/2\n", "");
+        let (_, errors) = parse_each(&input);
+        assert!(errors[0].0.starts_with("[Event \"Live Chess - Chess960\"]"));
+    }
+
+    #[test]
+    fn parses_games() {
+        let games = ChessParser::parse(GAMES).unwrap().collect::<Vec<_>>();
+        assert_eq!(games.len(), 2);
+        let (first, second) = (&games[0], &games[1]);
+        assert_eq!(
+            (first.white.as_str(), first.black.as_str()),
+            ("alice", "bob")
+        );
+        assert_eq!(first.white_display, "Alice");
+        assert_eq!(first.result, GameResult::WhiteWins);
+        assert_eq!(first.time, Time::Blitz);
+        assert_eq!(first.id, Some(42));
+        assert_eq!(first.variant, None);
+        assert_eq!(second.result, GameResult::Draw);
+        assert_eq!(second.time, Time::Bullet);
+        assert_eq!(second.variant.as_deref(), Some("Chess960"));
+        assert_eq!(
+            second.opening.as_deref(),
+            Some("Sicilian Defense Najdorf Variation")
+        );
+    }
+}
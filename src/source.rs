@@ -0,0 +1,98 @@
+use reqwest::Client;
+use serde::Deserialize;
+
+use crate::error::DownloadError;
+use crate::{Archive, Archives};
+
+/// A site games can be downloaded from.
+// Only implemented in this crate, so the futures don't need to promise to be `Send`.
+#[allow(async_fn_in_trait)]
+pub trait GameSource {
+    /// Lists the monthly archives of `username`.
+    async fn archives(&self, client: &Client, username: &str) -> Result<Archives, DownloadError>;
+
+    /// URL of the archive of `username` for a year and month, without listing the archives.
+    fn month_url(&self, username: &str, month: (i64, u32)) -> String;
+
+    /// URL of the PGN of all games in `archive`.
+    fn pgn_url(&self, archive: &Archive) -> String;
+
+    /// User whose games are at `url`, for the archive and PGN URLs above.
+    fn username_of(&self, url: &str) -> Option<String>;
+
+    /// URL of the daily games `username` is currently playing, if the site lists them.
+    fn ongoing_games_url(&self, username: &str) -> Option<String>;
+}
+
+/// chess.com's Published-Data API.
+pub struct ChessCom {
+    api_base: String,
+}
+
+impl ChessCom {
+    /// Uses the API at `api_base`, normally https://api.chess.com/pub.
+    pub fn new(api_base: &str) -> ChessCom {
+        ChessCom {
+            api_base: api_base.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    pub fn player_url(&self, username: &str) -> String {
+        format!("{}/player/{}", self.api_base, username)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct JSONArchivesContainer {
+    archives: Vec<String>,
+}
+
+impl GameSource for ChessCom {
+    async fn archives(&self, client: &Client, username: &str) -> Result<Archives, DownloadError> {
+        let archives_url = format!("{}/games/archives", self.player_url(username));
+        let container = async {
+            client
+                .get(archives_url)
+                .send()
+                .await?
+                .json::<JSONArchivesContainer>()
+                .await
+        }
+        .await
+        .map_err(|source| DownloadError::ArchiveListFailed {
+            username: username.to_owned(),
+            source,
+        })?;
+        Ok(container
+            .archives
+            .into_iter()
+            .map(|url| Archive {
+                username: username.to_owned(),
+                url,
+            })
+            .collect())
+    }
+
+    fn month_url(&self, username: &str, (year, month): (i64, u32)) -> String {
+        format!(
+            "{}/games/{:04}/{:02}",
+            self.player_url(username),
+            year,
+            month
+        )
+    }
+
+    fn pgn_url(&self, archive: &Archive) -> String {
+        format!("{}/pgn", archive.url)
+    }
+
+    fn username_of(&self, url: &str) -> Option<String> {
+        let path = url.strip_prefix(&format!("{}/player/", self.api_base))?;
+        let username = path.split('/').next()?;
+        (!username.is_empty()).then(|| username.to_owned())
+    }
+
+    fn ongoing_games_url(&self, username: &str) -> Option<String> {
+        Some(format!("{}/games", self.player_url(username)))
+    }
+}
@@ -1,13 +1,16 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
 use strum::Display;
 
-#[derive(Debug, PartialEq, Eq, Copy, Clone, Hash, Display)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Hash, Display, Default)]
 pub enum Time {
+    #[default]
     None,
     Misc,
     Bullet,
     Blitz,
     Rapid,
-    #[allow(dead_code)]
     Daily,
 }
 impl Time {
@@ -30,36 +33,480 @@ impl Time {
             Self::Misc
         }
     }
+
+    /// Maps chess.com's `TimeClass` header values.
+    pub fn from_time_class(val: &str) -> Option<Time> {
+        match val {
+            "bullet" => Some(Self::Bullet),
+            "blitz" => Some(Self::Blitz),
+            "rapid" => Some(Self::Rapid),
+            "daily" => Some(Self::Daily),
+            _ => None,
+        }
+    }
 }
-impl Default for Time {
-    fn default() -> Self {
-        Time::None
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum GameResult {
+    #[default]
+    None,
+    WhiteWins,
+    BlackWins,
+    Draw,
+    Ongoing,
+}
+impl GameResult {
+    pub fn parse(val: &str) -> GameResult {
+        match val {
+            "1-0" => Self::WhiteWins,
+            "0-1" => Self::BlackWins,
+            "1/2-1/2" => Self::Draw,
+            "*" => Self::Ongoing,
+            _ => Self::None,
+        }
     }
 }
 
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+pub struct Date {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+}
+impl Date {
+    /// Parses the `YYYY.MM.DD` format of PGN date tags.
+    pub fn parse_pgn(val: &str) -> Option<Date> {
+        Self::parse_with(val, '.')
+    }
+
+    fn parse_with(val: &str, sep: char) -> Option<Date> {
+        let mut parts = val.splitn(3, sep);
+        let year = parts.next()?.parse().ok()?;
+        let month = parts.next()?.parse().ok()?;
+        let day = parts.next()?.parse().ok()?;
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return None;
+        }
+        Some(Date { year, month, day })
+    }
+
+    /// Days since 1970-01-01.
+    fn to_days(self) -> i64 {
+        let year = if self.month <= 2 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = i64::from(self.month);
+        let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5
+            + i64::from(self.day)
+            - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146097 + day_of_era - 719468
+    }
+
+    /// Today in UTC.
+    pub fn today() -> Date {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Date::from_days(now.as_secs() as i64 / 86400)
+    }
+
+    pub fn year_month(self) -> (i64, u32) {
+        (self.year, self.month)
+    }
+
+    fn from_days(days: i64) -> Date {
+        let days = days + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = year_of_era + era * 400;
+        Date {
+            year: if month <= 2 { year + 1 } else { year },
+            month,
+            day,
+        }
+    }
+}
+impl std::str::FromStr for Date {
+    type Err = String;
+    /// Parses the `YYYY-MM-DD` format.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with(s, '-').ok_or_else(|| format!("expected YYYY-MM-DD, found `{}`", s))
+    }
+}
+impl std::fmt::Display for Date {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// Seconds since the Unix epoch of the `UTCDate` and `UTCTime` tags.
+pub fn utc_timestamp(date: &str, time: &str) -> Option<i64> {
+    let date = Date::parse_pgn(date)?;
+    let mut parts = time.splitn(3, ':');
+    let hours: i64 = parts.next()?.parse().ok()?;
+    let minutes: i64 = parts.next()?.parse().ok()?;
+    let seconds: i64 = parts.next()?.parse().ok()?;
+    Some(date.to_days() * 86400 + hours * 3600 + minutes * 60 + seconds)
+}
+
+/// The current time in UTC, e.g. `2024-01-15T10:30:00Z`.
+pub fn utc_now() -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let seconds = now % 86400;
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        Date::from_days(now / 86400),
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Extracts the game ID from a chess.com game URL, e.g. `https://www.chess.com/game/live/123`
+/// or the older `https://www.chess.com/daily/game/123`.
+pub fn game_id_from_link(link: &str) -> Option<u64> {
+    let path = link.split(['?', '#']).next()?;
+    path.trim_end_matches('/').rsplit('/').next()?.parse().ok()
+}
+
+/// The moves of a move text in SAN, without move numbers, comments, variations, annotations and the
+/// result. Also accepts move numbers attached to the move, e.g. `1.e4`.
+pub fn san_moves(movetext: &str) -> Vec<String> {
+    let mut plain = String::with_capacity(movetext.len());
+    let (mut comment, mut variation) = (false, 0);
+    for c in movetext.chars() {
+        match c {
+            '{' if !comment => comment = true,
+            '}' if comment => {
+                comment = false;
+                plain.push(' ');
+            }
+            '(' if !comment => variation += 1,
+            ')' if !comment && variation > 0 => {
+                variation -= 1;
+                plain.push(' ');
+            }
+            _ if comment || variation > 0 => (),
+            c => plain.push(c),
+        }
+    }
+    plain
+        .split_whitespace()
+        .filter(|token| !matches!(*token, "1-0" | "0-1" | "1/2-1/2" | "*"))
+        .map(|token| {
+            token
+                .trim_start_matches(|c: char| c.is_ascii_digit() || c == '.')
+                .trim_end_matches(['!', '?'])
+        })
+        .filter(|token| !token.is_empty() && !token.starts_with('$'))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The clock times of the `[%clk H:MM:SS]` annotations in a move text, in the order of the moves.
+/// Annotations that can't be read are skipped.
+pub fn clock_times(movetext: &str) -> Vec<Duration> {
+    movetext
+        .split("[%clk ")
+        .skip(1)
+        .filter_map(|annotation| {
+            let clock = annotation.split(']').next()?.trim();
+            let mut seconds = 0.0;
+            for part in clock.split(':') {
+                seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+            }
+            Some(Duration::from_secs_f64(seconds))
+        })
+        .collect()
+}
+
 #[derive(Default, Debug)]
 pub struct Game {
     pub pgn: String,
     pub time: Time,
+    /// Lowercased names of the players, for matching them with the usernames.
     pub white: String,
     pub black: String,
+    /// Names of the players as written in the game.
+    pub white_display: String,
+    pub black_display: String,
+    pub result: GameResult,
+    /// Chess variant, `None` for standard chess.
+    pub variant: Option<String>,
+    /// Name of the opening, e.g. "Sicilian Defense: Najdorf Variation".
+    pub opening: Option<String>,
+    /// Start of the game in seconds since the Unix epoch.
+    pub timestamp: Option<i64>,
+    /// Starting position for games that don't begin from the standard position.
+    pub fen: Option<String>,
+    /// chess.com game ID taken from the `Link` tag.
+    pub id: Option<u64>,
+    /// chess.com's accuracy scores, only available from the JSON archives.
+    pub white_accuracy: Option<f32>,
+    pub black_accuracy: Option<f32>,
+    /// Whether the game is rated, only available from the JSON archives.
+    pub rated: Option<bool>,
+    /// Time left on the clock after each move, only read with --export-clocks.
+    pub clocks: Vec<Duration>,
+    /// All header tags in the order they appear.
+    pub headers: Vec<(String, String)>,
+    pub moves: String,
 }
 
-#[derive(Hash, PartialEq, Eq, Display)]
+const SEVEN_TAG_ROSTER: [&str; 7] = ["Event", "Site", "Date", "Round", "White", "Black", "Result"];
+const MAX_LINE_LENGTH: usize = 80;
+
+impl Game {
+    /// Value of the first header tag named `attr`.
+    pub fn header(&self, attr: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(a, _)| a == attr)
+            .map(|(_, val)| val.as_str())
+    }
+
+    /// Name of the file of this game alone without extension, e.g. `2023-05-01_white_vs_black`.
+    pub fn file_stem(&self, offset: i64) -> String {
+        let date = self
+            .local_date(offset)
+            .map_or_else(|| "unknown".to_owned(), |date| date.to_string());
+        format!(
+            "{}_{}_vs_{}",
+            date,
+            sanitize_filename(&self.white),
+            sanitize_filename(&self.black)
+        )
+    }
+
+    /// Day the game started on in a timezone `offset` seconds ahead of UTC. Falls back to the
+    /// `Date` tag when the UTC tags are missing.
+    pub fn local_date(&self, offset: i64) -> Option<Date> {
+        match self.timestamp {
+            Some(timestamp) => Some(Date::from_days((timestamp + offset).div_euclid(86400))),
+            None => Date::parse_pgn(self.header("Date")?),
+        }
+    }
+
+    /// Identifies a game independently of trailing whitespace.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.pgn.trim_end().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Identifies the moves of a game regardless of the header tags, comments, annotations and
+    /// formatting. `None` if the game has no moves.
+    pub fn moves_fingerprint(&self) -> Option<u64> {
+        let moves = san_moves(&self.moves);
+        if moves.is_empty() {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        moves.hash(&mut hasher);
+        Some(hasher.finish())
+    }
+
+    /// Removes the comments, e.g. clock annotations, from the moves.
+    pub fn strip_comments(&mut self) {
+        let mut moves = String::with_capacity(self.moves.len());
+        let mut comment = false;
+        for c in self.moves.chars() {
+            match c {
+                '{' => comment = true,
+                '}' if comment => comment = false,
+                c if !comment => moves.push(c),
+                _ => (),
+            }
+        }
+        self.moves = moves.split_whitespace().collect::<Vec<_>>().join(" ");
+        self.pgn = format!("{}\n{}\n\n", &self.pgn[..self.headers_end()], self.moves);
+    }
+
+    /// Whether `username` delivered mate, or `None` if the game didn't end in checkmate.
+    pub fn checkmate_by(&self, username: &str) -> Option<bool> {
+        if !self
+            .header("Termination")
+            .is_some_and(|t| t.contains("checkmate"))
+        {
+            return None;
+        }
+        match self.result {
+            GameResult::WhiteWins => Some(self.white == username),
+            GameResult::BlackWins => Some(self.black == username),
+            _ => None,
+        }
+    }
+
+    /// Whether `username` played the game as either color.
+    pub fn played_by(&self, username: &str) -> bool {
+        self.white == username || self.black == username
+    }
+
+    /// Name of `username` as written in the PGN, with chess.com's casing, if they played the game.
+    pub fn display_name_of(&self, username: &str) -> Option<&str> {
+        if self.white == username {
+            Some(&self.white_display)
+        } else if self.black == username {
+            Some(&self.black_display)
+        } else {
+            None
+        }
+    }
+
+    /// Accuracy of `username` in the game, if they played it and it was analyzed.
+    pub fn accuracy_of(&self, username: &str) -> Option<f32> {
+        if self.white == username {
+            self.white_accuracy
+        } else if self.black == username {
+            self.black_accuracy
+        } else {
+            None
+        }
+    }
+
+    /// Whether the game starts with `moves`, given in SAN.
+    pub fn starts_with_moves(&self, moves: &[String]) -> bool {
+        san_moves(&self.moves).starts_with(moves)
+    }
+
+    /// Number of moves, counting each move number once.
+    pub fn num_moves(&self) -> u32 {
+        self.moves
+            .split_whitespace()
+            .filter(|token| {
+                token.starts_with(|c: char| c.is_ascii_digit())
+                    && token.ends_with('.')
+                    && !token.ends_with("...")
+            })
+            .count() as u32
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.header("Termination")
+            .is_some_and(|t| t.contains("aborted"))
+    }
+
+    /// Offset in `pgn` right after the last header tag.
+    fn headers_end(&self) -> usize {
+        let mut offset = 0;
+        let mut end = 0;
+        for line in self.pgn.split_inclusive('\n') {
+            if line.starts_with('[') {
+                end = offset + line.len();
+            } else if !line.trim().is_empty() {
+                break;
+            }
+            offset += line.len();
+        }
+        end
+    }
+
+    /// Adds a header tag after the existing ones.
+    pub fn add_header(&mut self, attr: &str, val: &str) {
+        self.pgn
+            .insert_str(self.headers_end(), &format!("[{} \"{}\"]\n", attr, val));
+        self.headers.push((attr.to_owned(), val.to_owned()));
+    }
+
+    /// Removes the header tags for which `keep` returns false, leaving the moves untouched.
+    pub fn retain_headers<F: Fn(&str) -> bool>(&mut self, keep: F) {
+        self.headers.retain(|(attr, _)| keep(attr));
+        let mut pgn = self
+            .headers
+            .iter()
+            .map(|(attr, val)| format!("[{} \"{}\"]\n", attr, val))
+            .collect::<String>();
+        pgn.push_str(&self.pgn[self.headers_end()..]);
+        self.pgn = pgn;
+    }
+
+    /// The header tags of the game without the moves.
+    pub fn header_section(&self) -> String {
+        let mut s = self
+            .pgn
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .take_while(|line| line.starts_with('['))
+            .map(|line| format!("{}\n", line))
+            .collect::<String>();
+        s.push('\n');
+        s
+    }
+
+    /// Formats the game with the Seven Tag Roster first and the moves wrapped at 80 columns.
+    pub fn pretty_pgn(&self) -> String {
+        let mut s = String::new();
+        for tag in SEVEN_TAG_ROSTER.iter() {
+            let val = self.header(tag).unwrap_or("?");
+            s.push_str(&format!("[{} \"{}\"]\n", tag, val));
+        }
+        for (attr, val) in &self.headers {
+            if !SEVEN_TAG_ROSTER.contains(&attr.as_str()) {
+                s.push_str(&format!("[{} \"{}\"]\n", attr, val));
+            }
+        }
+        s.push('\n');
+
+        let mut line_length = 0;
+        for token in self.moves.split_whitespace() {
+            if line_length > 0 && line_length + 1 + token.len() > MAX_LINE_LENGTH {
+                s.push('\n');
+                line_length = 0;
+            } else if line_length > 0 {
+                s.push(' ');
+                line_length += 1;
+            }
+            s.push_str(token);
+            line_length += token.len();
+        }
+        s.push_str("\n\n");
+        s
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Clone, Display)]
 pub enum Color {
     None,
     White,
     Black,
 }
-#[derive(Hash, PartialEq, Eq)]
+#[derive(Hash, PartialEq, Eq, Clone)]
 pub struct PGNMetadata {
     pub username: String,
     pub color: Color,
+    pub variant: Option<String>,
     pub time: Time,
+    /// Name of the group of time classes `time` was merged into with --merge-time.
+    pub time_group: Option<String>,
+    /// Year and month of the archive the games come from, with --by-archive.
+    pub month: Option<(i64, u32)>,
+    /// Opening name, with --group-by-opening-name.
+    pub opening: Option<String>,
+    /// Year the games were played in, with --by-year.
+    pub year: Option<String>,
 }
 
 impl PGNMetadata {
-    pub fn from_game(username: &String, game: &Game, ignore_time: bool) -> PGNMetadata {
+    pub fn from_game(
+        username: &String,
+        game: &Game,
+        ignore_time: bool,
+        ignore_variant: bool,
+    ) -> PGNMetadata {
         PGNMetadata {
             username: username.clone(),
             color: if *username == game.white {
@@ -67,27 +514,194 @@ impl PGNMetadata {
             } else {
                 Color::Black
             },
+            variant: if ignore_variant {
+                None
+            } else {
+                game.variant.clone()
+            },
             time: if ignore_time { Time::None } else { game.time },
+            time_group: None,
+            month: None,
+            opening: None,
+            year: None,
+        }
+    }
+    /// Puts the time class into the first group of `time_groups` that contains it.
+    pub fn merge_time(&mut self, time_groups: &[(Vec<Time>, String)]) {
+        if let Some((_, name)) = time_groups
+            .iter()
+            .find(|(times, _)| times.contains(&self.time))
+        {
+            self.time = Time::None;
+            self.time_group = Some(name.clone());
+        }
+    }
+
+    /// Name of the time class or group of time classes, if the games are sorted by time.
+    pub fn time_name(&self) -> Option<String> {
+        match (&self.time_group, self.time) {
+            (Some(group), _) => Some(group.clone()),
+            (None, Time::None) => None,
+            (None, time) => Some(time.to_string()),
         }
     }
+
     pub fn from_username(username: &str) -> PGNMetadata {
         PGNMetadata {
             username: String::from(username),
             color: Color::None,
+            variant: None,
             time: Time::None,
+            time_group: None,
+            month: None,
+            opening: None,
+            year: None,
         }
     }
 }
 
-impl std::fmt::Display for PGNMetadata {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let mut r = write!(f, "{}", self.username);
+/// Replaces characters that are not allowed in file names on some platforms with `_`. Names of
+/// only dots, like `..`, are replaced too since they refer to directories.
+pub fn sanitize_filename(name: &str) -> String {
+    if !name.is_empty() && name.chars().all(|c| c == '.') {
+        return "_".repeat(name.len());
+    }
+    name.chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+const MAX_OPENING_FILE_NAME_LENGTH: usize = 64;
+
+/// Opening name in CamelCase without punctuation, shortened since some names are very long, e.g.
+/// "Sicilian Defense: Najdorf Variation" becomes `SicilianDefenseNajdorfVariation`.
+fn opening_file_name(opening: &str) -> String {
+    opening
+        .split(|c: char| !c.is_alphanumeric())
+        .flat_map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .into_iter()
+                .flat_map(char::to_uppercase)
+                .chain(chars)
+        })
+        .take(MAX_OPENING_FILE_NAME_LENGTH)
+        .collect()
+}
+
+impl PGNMetadata {
+    /// Output file name, optionally without the username. The username is kept if it would
+    /// otherwise be empty.
+    pub fn file_name(&self, include_username: bool, extension: &str) -> String {
+        let mut parts = Vec::new();
+        if include_username {
+            parts.push(sanitize_filename(&self.username));
+        }
         if self.color != Color::None {
-            r = r.and(write!(f, "_{}", self.color))
+            parts.push(self.color.to_string());
         }
-        if self.time != Time::None {
-            r = r.and(write!(f, "_{}", self.time))
+        if let Some(variant) = &self.variant {
+            parts.push(sanitize_filename(variant));
         }
-        r.and(write!(f, ".pgn"))
+        if let Some(time) = self.time_name() {
+            parts.push(sanitize_filename(&time));
+        }
+        if let Some(opening) = &self.opening {
+            parts.push(opening_file_name(opening));
+        }
+        if let Some(year) = &self.year {
+            parts.push(year.clone());
+        }
+        if let Some((year, month)) = self.month {
+            parts.push(format!("{:04}-{:02}", year, month));
+        }
+        if parts.is_empty() {
+            parts.push(sanitize_filename(&self.username));
+        }
+        format!("{}.{}", parts.join("_"), extension)
+    }
+}
+
+impl std::fmt::Display for PGNMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.file_name(true, "pgn"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn game_id() {
+        assert_eq!(
+            game_id_from_link("https://www.chess.com/game/live/123?move=4"),
+            Some(123)
+        );
+        assert_eq!(
+            game_id_from_link("https://www.chess.com/daily/game/456/"),
+            Some(456)
+        );
+        assert_eq!(game_id_from_link("https://www.chess.com/game/live/"), None);
+    }
+
+    #[test]
+    fn sanitize_usernames() {
+        assert_eq!(sanitize_filename("a/b"), "a_b");
+        assert_eq!(sanitize_filename("a\\b"), "a_b");
+        assert_eq!(sanitize_filename("con:<1>?"), "con__1__");
+        assert_eq!(sanitize_filename(".."), "__");
+        assert_eq!(sanitize_filename("../x"), ".._x");
+        assert_eq!(sanitize_filename("magnus.carlsen"), "magnus.carlsen");
+        let mut metadata = PGNMetadata::from_username("a/b");
+        metadata.color = Color::White;
+        metadata.time = Time::Blitz;
+        assert_eq!(metadata.to_string(), "a_b_White_Blitz.pgn");
+        // The original name is kept for the API and matching the players.
+        assert_eq!(metadata.username, "a/b");
+    }
+
+    #[test]
+    fn game_file_stem() {
+        let game = Game {
+            white: "a/b".to_owned(),
+            black: "..".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(game.file_stem(0), "unknown_a_b_vs___");
+    }
+
+    #[test]
+    fn san_moves_skips_annotations() {
+        let movetext = "1. e4 {[%clk 0:03:00]} e5!? (1... c5 2. Nf3) 2.Nf3 $1 Nc6?? 3. O-O-O+ 1-0";
+        assert_eq!(san_moves(movetext), ["e4", "e5", "Nf3", "Nc6", "O-O-O+"]);
+    }
+
+    #[test]
+    fn clock_times_of_moves() {
+        let movetext =
+            "1. e4 {[%clk 0:03:01]} e5 {[%clk 0:02:59.5]} 2. Nf3 {[%clk bad]} Nc6 {[%clk 1:00]}";
+        assert_eq!(
+            clock_times(movetext),
+            [
+                Duration::from_secs(181),
+                Duration::from_secs_f64(179.5),
+                Duration::from_secs(60),
+            ]
+        );
+    }
+
+    #[test]
+    fn time_control() {
+        assert_eq!(Time::parse("60+1"), Time::Bullet);
+        assert_eq!(Time::parse("300"), Time::Blitz);
+        assert_eq!(Time::parse("900"), Time::Rapid);
+        assert_eq!(Time::parse("1800"), Time::Misc);
+        assert_eq!(Time::parse("1/259200"), Time::Misc);
     }
 }
@@ -0,0 +1,454 @@
+use chess_dl::{download_all_games, ChessCom, DownloadError, Options};
+use clap::Parser;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use httpmock::prelude::*;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+const GAME: &str = r#"[Event "Live Chess"]
+[Site "Chess.com"]
+[Date "2023.05.01"]
+[White "Alice"]
+[Black "Bob"]
+[Result "1-0"]
+[TimeControl "180"]
+[Link "https://www.chess.com/game/live/1"]
+
+1. e4 e5 2. Qh5 Nc6 3. Bc4 Nf6 4. Qxf7# 1-0
+
+"#;
+
+const ARCHIVE: &str = "/pub/player/alice/games/2023/05";
+
+/// Lists the archives of `username` at the given paths.
+fn mock_archives(server: &MockServer, username: &str, archives: &[&str]) {
+    let archives = archives
+        .iter()
+        .map(|path| server.url(*path))
+        .collect::<Vec<_>>();
+    server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/pub/player/{}/games/archives", username));
+        then.status(200).json_body(json!({ "archives": archives }));
+    });
+}
+
+/// Options for downloading alice's games from `server` to `dir`, with a manifest and a report.
+fn options(server: &MockServer, dir: &Path, args: &[&str]) -> Options {
+    let api_base = server.url("/pub");
+    let dir = dir.to_str().unwrap();
+    let manifest = format!("{}/manifest.json", dir);
+    let report = format!("{}/report.json", dir);
+    let mut all = vec![
+        "chess_dl",
+        "alice",
+        "-o",
+        dir,
+        "--api-base",
+        &api_base,
+        "--manifest",
+        &manifest,
+        "--report-json",
+        &report,
+        "--backoff",
+        "constant",
+        "--no-jitter",
+    ];
+    all.extend_from_slice(args);
+    Options::parse_from(all)
+}
+
+async fn download(opt: &Options, token: &CancellationToken) -> Result<(), DownloadError> {
+    let (_pause, paused) = watch::channel(false);
+    let source = ChessCom::new(&opt.api_base);
+    download_all_games(opt, &source, token, paused).await
+}
+
+fn read_json(path: &Path) -> Value {
+    serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+}
+
+/// Number of games written to each file according to the manifest.
+fn games_by_file(dir: &Path) -> Vec<(String, u64)> {
+    read_json(&dir.join("manifest.json"))
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| {
+            let file = Path::new(entry["file"].as_str().unwrap());
+            (
+                file.file_name().unwrap().to_str().unwrap().to_owned(),
+                entry["games"].as_u64().unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn downloads_archive() {
+    let server = MockServer::start_async().await;
+    mock_archives(&server, "alice", &[ARCHIVE]);
+    let pgn = server.mock(|when, then| {
+        when.method(GET).path(format!("{}/pgn", ARCHIVE));
+        then.status(200).body(GAME);
+    });
+    let dir = tempfile::tempdir().unwrap();
+
+    let opt = options(&server, dir.path(), &[]);
+    download(&opt, &CancellationToken::new()).await.unwrap();
+
+    pgn.assert();
+    assert_eq!(
+        games_by_file(dir.path()),
+        [("alice_White.pgn".to_owned(), 1)]
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("alice_White.pgn")).unwrap(),
+        GAME
+    );
+    assert_eq!(
+        read_json(&dir.path().join("report.json"))["failed"],
+        json!([])
+    );
+}
+
+#[tokio::test]
+async fn empty_archive() {
+    let server = MockServer::start_async().await;
+    mock_archives(&server, "alice", &[ARCHIVE]);
+    let pgn = server.mock(|when, then| {
+        when.method(GET).path(format!("{}/pgn", ARCHIVE));
+        then.status(200).body("");
+    });
+    let dir = tempfile::tempdir().unwrap();
+
+    let opt = options(&server, dir.path(), &["-a", "2"]);
+    download(&opt, &CancellationToken::new()).await.unwrap();
+
+    // Empty archives are retried in case the API had a hiccup.
+    pgn.assert_hits(2);
+    assert_eq!(games_by_file(dir.path()), []);
+    assert_eq!(
+        read_json(&dir.path().join("report.json"))["failed"],
+        json!([])
+    );
+}
+
+#[tokio::test]
+async fn retries_server_error() {
+    let server = MockServer::start_async().await;
+    mock_archives(&server, "alice", &[ARCHIVE]);
+    let error = server.mock(|when, then| {
+        when.method(GET).path(format!("{}/pgn", ARCHIVE));
+        then.status(500);
+    });
+    let dir = tempfile::tempdir().unwrap();
+
+    let opt = options(&server, dir.path(), &["-a", "3"]);
+    let recover = async {
+        // Answer the retry once the first attempt failed.
+        while error.hits_async().await == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        error.delete_async().await;
+        server.mock(|when, then| {
+            when.method(GET).path(format!("{}/pgn", ARCHIVE));
+            then.status(200).body(GAME);
+        })
+    };
+    let token = CancellationToken::new();
+    let (downloaded, pgn) = tokio::join!(download(&opt, &token), recover);
+    downloaded.unwrap();
+
+    pgn.assert();
+    assert_eq!(
+        games_by_file(dir.path()),
+        [("alice_White.pgn".to_owned(), 1)]
+    );
+    assert_eq!(
+        read_json(&dir.path().join("report.json"))["failed"],
+        json!([])
+    );
+}
+
+#[tokio::test]
+async fn fails_archive_after_attempts() {
+    let server = MockServer::start_async().await;
+    mock_archives(&server, "alice", &[ARCHIVE]);
+    let pgn = server.mock(|when, then| {
+        when.method(GET).path(format!("{}/pgn", ARCHIVE));
+        then.status(500);
+    });
+    let dir = tempfile::tempdir().unwrap();
+
+    let opt = options(&server, dir.path(), &["-a", "2"]);
+    download(&opt, &CancellationToken::new()).await.unwrap();
+
+    pgn.assert_hits(2);
+    assert_eq!(games_by_file(dir.path()), []);
+    assert_eq!(
+        read_json(&dir.path().join("report.json"))["failed"],
+        json!([server.url(format!("{}/pgn", ARCHIVE))])
+    );
+}
+
+#[tokio::test]
+async fn missing_archive() {
+    let server = MockServer::start_async().await;
+    mock_archives(&server, "alice", &[ARCHIVE]);
+    let pgn = server.mock(|when, then| {
+        when.method(GET).path(format!("{}/pgn", ARCHIVE));
+        then.status(404);
+    });
+    let dir = tempfile::tempdir().unwrap();
+
+    let opt = options(&server, dir.path(), &["-a", "3"]);
+    download(&opt, &CancellationToken::new()).await.unwrap();
+
+    // Client errors aren't retried.
+    pgn.assert_hits(1);
+    assert_eq!(
+        read_json(&dir.path().join("report.json"))["failed"],
+        json!([server.url(format!("{}/pgn", ARCHIVE))])
+    );
+}
+
+#[tokio::test]
+async fn unknown_user() {
+    let server = MockServer::start_async().await;
+    let archives = server.mock(|when, then| {
+        when.method(GET).path("/pub/player/alice/games/archives");
+        then.status(404)
+            .json_body(json!({ "code": 0, "message": "User \"alice\" not found." }));
+    });
+    let dir = tempfile::tempdir().unwrap();
+
+    let opt = options(&server, dir.path(), &[]);
+    let result = download(&opt, &CancellationToken::new()).await;
+
+    archives.assert();
+    match result {
+        Err(DownloadError::ArchiveListFailed { username, .. }) => assert_eq!(username, "alice"),
+        other => panic!("expected ArchiveListFailed, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn cancellation() {
+    let server = MockServer::start_async().await;
+    mock_archives(&server, "alice", &[ARCHIVE]);
+    let pgn = server.mock(|when, then| {
+        when.method(GET).path(format!("{}/pgn", ARCHIVE));
+        then.status(200).body(GAME).delay(Duration::from_secs(30));
+    });
+    let dir = tempfile::tempdir().unwrap();
+
+    let opt = options(&server, dir.path(), &[]);
+    let token = CancellationToken::new();
+    let cancel = async {
+        while pgn.hits_async().await == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        token.cancel();
+    };
+    let (result, ()) = tokio::time::timeout(Duration::from_secs(10), async {
+        tokio::join!(download(&opt, &token), cancel)
+    })
+    .await
+    .expect("the download didn't stop after it was cancelled");
+
+    assert!(matches!(result, Err(DownloadError::Cancelled)));
+    assert!(!dir.path().join("alice_White.pgn").exists());
+}
+
+#[tokio::test]
+async fn counts_each_archive_once() {
+    let server = MockServer::start_async().await;
+    let (good, empty, failing) = (
+        "/pub/player/alice/games/2023/03",
+        "/pub/player/alice/games/2023/04",
+        "/pub/player/alice/games/2023/05",
+    );
+    mock_archives(&server, "alice", &[good, empty, failing]);
+    for (path, status, body) in [(good, 200, GAME), (empty, 200, ""), (failing, 500, "")] {
+        server.mock(|when, then| {
+            when.method(GET).path(format!("{}/pgn", path));
+            then.status(status).body(body);
+        });
+    }
+    let dir = tempfile::tempdir().unwrap();
+
+    let opt = options(&server, dir.path(), &["-a", "2"]);
+    download(&opt, &CancellationToken::new()).await.unwrap();
+
+    let report = read_json(&dir.path().join("report.json"));
+    assert_eq!(
+        report["archives"],
+        json!({
+            "total": 3,
+            "downloaded": 1,
+            "empty": 1,
+            "failed": 1,
+            "too_large": 0,
+            "past_deadline": 0,
+            "unchanged": 0,
+            "cached": 0,
+            "skipped": 0,
+            "cancelled": 0,
+        })
+    );
+    assert_eq!(
+        report["failed"],
+        json!([server.url(format!("{}/pgn", failing))])
+    );
+}
+
+#[tokio::test]
+async fn flatten_single_user() {
+    let server = MockServer::start_async().await;
+    let bob_archive = "/pub/player/bob/games/2023/05";
+    mock_archives(&server, "alice", &[ARCHIVE]);
+    mock_archives(&server, "bob", &[bob_archive]);
+    for path in [ARCHIVE, bob_archive] {
+        server.mock(|when, then| {
+            when.method(GET).path(format!("{}/pgn", path));
+            then.status(200).body(GAME);
+        });
+    }
+
+    // A single user's files don't need the username.
+    let dir = tempfile::tempdir().unwrap();
+    let opt = options(&server, dir.path(), &["--flatten-single-user"]);
+    download(&opt, &CancellationToken::new()).await.unwrap();
+    assert_eq!(games_by_file(dir.path()), [("White.pgn".to_owned(), 1)]);
+
+    // With several users, the files keep the username so that they don't collide.
+    let dir = tempfile::tempdir().unwrap();
+    let opt = options(&server, dir.path(), &["--flatten-single-user", "bob"]);
+    download(&opt, &CancellationToken::new()).await.unwrap();
+    let mut files = games_by_file(dir.path());
+    files.sort();
+    assert_eq!(
+        files,
+        [
+            ("alice_White.pgn".to_owned(), 1),
+            ("bob_Black.pgn".to_owned(), 1)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn gzip_archive() {
+    let server = MockServer::start_async().await;
+    mock_archives(&server, "alice", &[ARCHIVE]);
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(GAME.as_bytes()).unwrap();
+    let gzipped = encoder.finish().unwrap();
+    server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("{}/pgn", ARCHIVE))
+            .header_exists("accept-encoding");
+        then.status(200)
+            .header("Content-Encoding", "gzip")
+            .body(gzipped);
+    });
+    let dir = tempfile::tempdir().unwrap();
+
+    let opt = options(&server, dir.path(), &[]);
+    download(&opt, &CancellationToken::new()).await.unwrap();
+
+    assert_eq!(
+        games_by_file(dir.path()),
+        [("alice_White.pgn".to_owned(), 1)]
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("alice_White.pgn")).unwrap(),
+        GAME
+    );
+}
+
+#[tokio::test]
+async fn invalid_json_archive() {
+    let server = MockServer::start_async().await;
+    mock_archives(&server, "alice", &[ARCHIVE]);
+    server.mock(|when, then| {
+        when.method(GET).path(ARCHIVE);
+        then.status(200).body("{\"games\": [");
+    });
+    let dir = tempfile::tempdir().unwrap();
+
+    let opt = options(
+        &server,
+        dir.path(),
+        &["--json-games", "--max-parse-error-rate", "50"],
+    );
+    let result = download(&opt, &CancellationToken::new()).await;
+
+    // The archive counts as a game that failed to parse.
+    match result {
+        Err(DownloadError::TooManyParseErrors { rate, .. }) => assert_eq!(rate, 100.0),
+        other => panic!("expected TooManyParseErrors, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn verify_dropped_tags() {
+    let server = MockServer::start_async().await;
+    mock_archives(&server, "alice", &[ARCHIVE]);
+    server.mock(|when, then| {
+        when.method(GET).path(format!("{}/pgn", ARCHIVE));
+        then.status(200).body(GAME);
+    });
+    let dir = tempfile::tempdir().unwrap();
+
+    let opt = options(
+        &server,
+        dir.path(),
+        &["--verify", "--drop-tags", "Result,Black"],
+    );
+    download(&opt, &CancellationToken::new()).await.unwrap();
+
+    let pgn = std::fs::read_to_string(dir.path().join("alice_White.pgn")).unwrap();
+    assert!(pgn.starts_with("[Event \"Live Chess\"]"));
+    assert!(!pgn.contains("[Result ") && !pgn.contains("[Black "));
+}
+
+#[tokio::test]
+async fn latin1_sizes() {
+    let server = MockServer::start_async().await;
+    mock_archives(&server, "alice", &[ARCHIVE]);
+    let first = GAME.replace("Bob", "Muñoz");
+    let body = format!("{}{}", first, GAME);
+    server.mock(|when, then| {
+        when.method(GET).path(format!("{}/pgn", ARCHIVE));
+        then.status(200).body(body);
+    });
+    let dir = tempfile::tempdir().unwrap();
+    let toc = dir.path().join("toc.tsv");
+
+    let opt = options(
+        &server,
+        dir.path(),
+        &["--encoding", "latin1", "--toc", toc.to_str().unwrap()],
+    );
+    download(&opt, &CancellationToken::new()).await.unwrap();
+
+    // ñ takes one byte in Latin-1 instead of two.
+    let size = (first.len() + GAME.len() - 1) as u64;
+    let path = dir.path().join("alice_White.pgn");
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+    let manifest = read_json(&dir.path().join("manifest.json"));
+    assert_eq!(manifest[0]["bytes"], json!(size));
+    let toc = std::fs::read_to_string(toc).unwrap();
+    let offsets = toc
+        .lines()
+        .skip(1)
+        .map(|line| line.split('\t').nth(1).unwrap().parse::<u64>().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(offsets, [0, first.len() as u64 - 1]);
+}